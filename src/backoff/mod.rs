@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+
+/// Exponential backoff between consecutive attempts.
+///
+/// The delay before attempt `n` (counting from 0) is `initial * multiplier^n`
+/// capped by `max`, then reduced by a random fraction of at most `jitter`,
+/// so that clients failing at the same moment don't retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// delay before the first retry
+    pub initial: Duration,
+
+    /// upper bound for a single delay
+    pub max: Duration,
+
+    /// factor the delay grows by after each attempt
+    pub multiplier: f64,
+
+    /// fraction of the delay (from 0 to 1) that may be randomly cut off
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff{
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2.,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay to sleep before the given attempt.
+    ///
+    /// # Example:
+    /// ```
+    /// use rsoffkv::backoff::Backoff;
+    /// use std::time::Duration;
+    ///
+    /// let backoff = Backoff{
+    ///     initial: Duration::from_millis(100),
+    ///     max: Duration::from_millis(300),
+    ///     multiplier: 2.,
+    ///     jitter: 0.,
+    /// };
+    ///
+    /// assert_eq!(backoff.delay(0), Duration::from_millis(100));
+    /// assert_eq!(backoff.delay(1), Duration::from_millis(200));
+    /// assert_eq!(backoff.delay(5), Duration::from_millis(300));
+    ///
+    /// let jittered = Backoff{jitter: 0.5, ..backoff}.delay(1);
+    /// assert!(jittered >= Duration::from_millis(100) && jittered <= Duration::from_millis(200));
+    /// ```
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = self.multiplier.max(1.).powi(attempt.min(64) as i32);
+        let delay = (self.initial.as_secs_f64() * factor).min(self.max.as_secs_f64());
+        let jitter = self.jitter.clamp(0., 1.) * random_fraction();
+
+        Duration::from_secs_f64(delay * (1. - jitter))
+    }
+}
+
+
+/// Returns a pseudo-random number from [0, 1), good enough to spread retries.
+fn random_fraction() -> f64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+    // splitmix64
+    let mut x = STATE.fetch_add(seed | 1, Ordering::Relaxed).wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
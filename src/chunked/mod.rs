@@ -0,0 +1,362 @@
+//! Values larger than the services accept (about 1 MB for ZooKeeper, 512 KB for Consul
+//! and 1.5 MB for etcd, exceeding the limit fails with `ServiceError`).
+//!
+//! A chunked value is split into chunks stored as children of its key, and the key
+//! itself holds a manifest naming the chunks and their checksums. A write creates
+//! the chunks of a new generation first and then switches the manifest to them
+//! with a `commit` checking both the manifest and the new chunks, so readers see
+//! either the old or the new value in full. The chunks of the old generation are erased
+//! afterwards; chunks left behind by writers that failed midway (or that lost
+//! the connection while committing) are erased by `ChunkedClient::gc`.
+//!
+//! The children of a chunked key are reserved for its chunks. A value takes as many
+//! checks in the commit as it has chunks, which counts towards the limit on the size of
+//! a transaction of the service (e.g. 64 operations for Consul), so the largest value
+//! is about `chunk_size * 63`.
+
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::time::{SystemTime,UNIX_EPOCH};
+
+use crate::client::{Client,WatchHandle};
+use crate::result::OffkvError;
+use crate::txn::{Transaction,TxnCheck,TxnOp,TxnOpResult};
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// Chunking settings, see `ChunkedClient::new`.
+#[derive(Clone, Debug)]
+pub struct ChunkedOptions {
+    /// maximal size of a chunk in bytes, must be below the limit of the service
+    pub chunk_size: usize,
+}
+
+impl Default for ChunkedOptions {
+    fn default() -> Self {
+        ChunkedOptions{
+            chunk_size: 256 * 1024,
+        }
+    }
+}
+
+/// Client storing values split into chunks.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::chunked::{ChunkedClient,ChunkedOptions};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let tables = ChunkedClient::new(&client, ChunkedOptions{chunk_size: 1024});
+/// let routes: Vec<u8> = (0..10_000u32).flat_map(|route| route.to_be_bytes()).collect();
+///
+/// let version = tables.set("/routes", &routes).unwrap();
+/// assert_eq!(tables.get("/routes", false).unwrap().0, version);
+/// assert_eq!(tables.get("/routes", false).unwrap().1, routes);
+///
+/// // 40 chunks of the current generation
+/// assert_eq!(client.get_children("/routes", false).unwrap().0.len(), 40);
+///
+/// // conditional update
+/// assert_eq!(tables.cas("/routes", b"stale", version + 1).unwrap(), 0);
+/// assert_ne!(tables.cas("/routes", b"short", version).unwrap(), 0);
+/// assert_eq!(tables.get("/routes", false).unwrap().1, b"short");
+///
+/// // nothing to collect, the old chunks are gone
+/// assert_eq!(tables.gc("/routes").unwrap(), 0);
+/// assert_eq!(client.get_children("/routes", false).unwrap().0.len(), 1);
+///
+/// tables.erase("/routes", 0).unwrap();
+/// assert_eq!(client.exists("/routes", false).unwrap().0, 0);
+/// ```
+#[derive(Clone)]
+pub struct ChunkedClient {
+    client: Client,
+    options: ChunkedOptions,
+}
+
+const MANIFEST_HEADER: &str = "chunked";
+
+struct Manifest {
+    generation: String,
+    size: usize,
+    checksums: Vec<u32>,
+}
+
+/// CRC-32 (IEEE).
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg()))
+    })
+}
+
+/// Returns a name of a new generation of chunks, unique with high probability.
+fn new_generation() -> String {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+    format!("{:x}{:x}{:x}", nanos, std::process::id(), WRITES.fetch_add(1, Ordering::Relaxed))
+}
+
+fn chunk_key(key: &str, generation: &str, index: usize) -> String {
+    format!("{}/{}-{:06}", key, generation, index)
+}
+
+impl Manifest {
+    fn empty() -> Self {
+        Manifest{generation: String::new(), size: 0, checksums: Vec::new()}
+    }
+
+    fn encode(&self) -> String {
+        let checksums: Vec<String> = self.checksums.iter().map(|checksum| format!("{:08x}", checksum)).collect();
+        format!("{}\n{}\n{}\n{}", MANIFEST_HEADER, self.generation, self.size, checksums.join(" "))
+    }
+
+    fn decode(manifest: &[u8]) -> Result<Self> {
+        let invalid = || OffkvError::Decode(String::from("not a chunked value"));
+
+        let manifest = std::str::from_utf8(manifest).map_err(|_| invalid())?;
+        let mut lines = manifest.split('\n');
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(invalid());
+        }
+
+        let generation = String::from(lines.next().ok_or_else(invalid)?);
+        let size = lines.next().and_then(|size| size.parse().ok()).ok_or_else(invalid)?;
+        let checksums = lines.next().ok_or_else(invalid)?
+            .split_whitespace()
+            .map(|checksum| u32::from_str_radix(checksum, 16).map_err(|_| invalid()))
+            .collect::<Result<_>>()?;
+
+        Ok(Manifest{generation, size, checksums})
+    }
+
+    fn chunks(&self, key: &str) -> Vec<String> {
+        (0..self.checksums.len()).map(|index| chunk_key(key, &self.generation, index)).collect()
+    }
+}
+
+impl ChunkedClient {
+    /// Creates a client storing values in chunks of `options.chunk_size` bytes at most.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the chunks and the manifests are stored with
+    /// * `options` - chunking settings, the same for all the writers of a key
+    pub fn new(client: &Client, options: ChunkedOptions) -> Self {
+        ChunkedClient{client: client.clone(), options}
+    }
+
+    /// Sets the value of the key, creating the key if needed.
+    ///
+    /// # Returns:
+    ///
+    /// * new version of the key (its manifest)
+    pub fn set<V: AsRef<[u8]>>(&self, key: &str, value: V) -> Result<i64> {
+        let value = value.as_ref();
+
+        self.client.options().conflicts.run_optimistic(|_| {
+            let (version, old) = match self.client.get_bytes(key, false) {
+                Ok((version, manifest, _)) => (version, Manifest::decode(&manifest)?),
+                Err(OffkvError::NoEntry) => match self.client.create(key, Manifest::empty().encode(), false) {
+                    Ok(version) => (version, Manifest::empty()),
+                    Err(OffkvError::EntryExists) => return Ok(None),
+                    Err(error) => return Err(error),
+                },
+                Err(error) => return Err(error),
+            };
+
+            self.write(key, value, version, &old)
+        })
+    }
+
+    /// Sets the value of the key if its version equals to the given one.
+    ///
+    /// # Returns:
+    ///
+    /// * new version of the key (its manifest), 0 if the version didn't match
+    pub fn cas<V: AsRef<[u8]>>(&self, key: &str, value: V, version: i64) -> Result<i64> {
+        let (current_version, manifest, _) = self.client.get_bytes(key, false)?;
+        if current_version != version {
+            return Ok(0);
+        }
+
+        Ok(self.write(key, value.as_ref(), version, &Manifest::decode(&manifest)?)?.unwrap_or(0))
+    }
+
+    /// Returns the current version (of the manifest) and the value of the key.
+    ///
+    /// # Arguments:
+    ///
+    /// * `key` - a chunked key
+    /// * `watch` - if true, a `WatchHandle` waiting for a change of the value is returned
+    ///
+    /// # Returns:
+    ///
+    /// * current version of the key
+    /// * the value
+    /// * (optional) `WatchHandle`
+    /// * `OffkvError::Decode` if the key is not chunked or the checksum of a chunk doesn't match
+    pub fn get(&self, key: &str, watch: bool) -> Result<(i64, Vec<u8>, Option<WatchHandle<'_>>)> {
+        // a concurrent write may erase the chunks being read
+        self.client.options().conflicts.run_optimistic(|_| {
+            let (version, manifest, watch_handle) = self.client.get_bytes(key, watch)?;
+            let manifest = Manifest::decode(&manifest)?;
+
+            let mut value = Vec::with_capacity(manifest.size);
+            for (chunk, checksum) in manifest.chunks(key).iter().zip(manifest.checksums.iter()) {
+                let chunk = match self.client.get_bytes(chunk, false) {
+                    Ok((_, chunk, _)) => chunk,
+                    Err(OffkvError::NoEntry) => return Ok(None),
+                    Err(error) => return Err(error),
+                };
+
+                if crc32(&chunk) != *checksum {
+                    return Err(OffkvError::Decode(String::from("checksum mismatch")));
+                }
+                value.extend(chunk);
+            }
+
+            if value.len() != manifest.size {
+                return Err(OffkvError::Decode(String::from("size mismatch")));
+            }
+            Ok(Some((version, value, watch_handle)))
+        })
+    }
+
+    /// Erases the chunks of the key's value not referenced by its manifest,
+    /// e.g. left by writers that failed midway.
+    ///
+    /// Must not run concurrently with writes of the key: the chunks of a write in progress
+    /// can't be told from the ones left behind, so they are erased too, making the write
+    /// start over (and fail with `OffkvError::TxnFailed` if that keeps happening).
+    ///
+    /// # Returns:
+    ///
+    /// * number of chunks erased
+    pub fn gc(&self, key: &str) -> Result<usize> {
+        let (_, manifest, _) = self.client.get_bytes(key, false)?;
+        let current = Manifest::decode(&manifest)?.chunks(key);
+
+        let (children, _) = self.client.get_children(key, false)?;
+        let mut erased = 0;
+        for orphan in children.iter().filter(|child| !current.contains(child)) {
+            match self.client.erase(orphan, 0) {
+                Ok(()) => erased += 1,
+                Err(OffkvError::NoEntry) => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(erased)
+    }
+
+    /// Erases the key together with its chunks.
+    ///
+    /// # Arguments:
+    ///
+    /// * `key` - a chunked key
+    /// * `version` - if not 0, erases the key only if its version (of the manifest)
+    ///   equals to the given one
+    pub fn erase(&self, key: &str, version: i64) -> Result<()> {
+        // the chunks are the key's descendants
+        self.client.erase(key, version)
+    }
+
+    /// Writes the chunks and switches the manifest to them.
+    ///
+    /// Returns the new version, `None` if the manifest or the chunks have changed.
+    fn write(&self, key: &str, value: &[u8], version: i64, old: &Manifest) -> Result<Option<i64>> {
+        let chunks: Vec<&[u8]> = value.chunks(self.options.chunk_size.max(1)).collect();
+        let manifest = Manifest{
+            generation: new_generation(),
+            size: value.len(),
+            checksums: chunks.iter().map(|chunk| crc32(chunk)).collect(),
+        };
+        let keys = manifest.chunks(key);
+
+        let mut versions = Vec::new();
+        for (chunk_key, chunk) in keys.iter().zip(chunks) {
+            match self.client.create(chunk_key, chunk, false) {
+                Ok(version) => versions.push(version),
+                Err(error) => {
+                    self.erase_chunks(&keys[..versions.len()]);
+                    return Err(error);
+                },
+            }
+        }
+
+        let mut checks = vec![TxnCheck{key, version}];
+        checks.extend(keys.iter().zip(versions).map(|(key, version)| TxnCheck{key, version}));
+        let encoded = manifest.encode();
+
+        match self.client.commit(Transaction{checks, ops: vec![TxnOp::Set{key, value: &encoded}]}) {
+            Ok(results) => {
+                self.erase_chunks(&old.chunks(key));
+                match results[..] {
+                    [TxnOpResult::Set(version)] => Ok(Some(version)),
+                    _ => unreachable!(),
+                }
+            },
+            Err(OffkvError::TxnFailed(_)) => {
+                self.erase_chunks(&keys);
+                Ok(None)
+            },
+            // the manifest may have been switched to the new chunks, they are left to `gc`
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Erases the chunks, leaving the ones that fail to `gc`.
+    fn erase_chunks(&self, chunks: &[String]) {
+        for chunk in chunks {
+            let _ = self.client.erase(chunk, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_the_reference_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let manifest = Manifest{generation: String::from("17a2b3c4"), size: 2049, checksums: vec![0, 0xcbf4_3926, 0xffff_ffff]};
+        let decoded = Manifest::decode(manifest.encode().as_bytes()).unwrap();
+
+        assert_eq!(decoded.generation, manifest.generation);
+        assert_eq!(decoded.size, manifest.size);
+        assert_eq!(decoded.checksums, manifest.checksums);
+        assert_eq!(decoded.chunks("/routes"), vec![
+            String::from("/routes/17a2b3c4-000000"),
+            String::from("/routes/17a2b3c4-000001"),
+            String::from("/routes/17a2b3c4-000002"),
+        ]);
+    }
+
+    #[test]
+    fn empty_manifest_round_trips() {
+        let decoded = Manifest::decode(Manifest::empty().encode().as_bytes()).unwrap();
+        assert_eq!((decoded.generation.as_str(), decoded.size), ("", 0));
+        assert!(decoded.chunks("/routes").is_empty());
+    }
+
+    #[test]
+    fn malformed_manifests_are_rejected() {
+        for manifest in ["", "plain value", "chunked", "chunked\ngen", "chunked\ngen\nbig\n",
+                         "chunked\ngen\n10\nnot-hex", "other\ngen\n10\n"].iter() {
+            match Manifest::decode(manifest.as_bytes()) {
+                Err(OffkvError::Decode(_)) => {},
+                _ => panic!("{:?} must fail to decode", manifest),
+            }
+        }
+        assert!(Manifest::decode(&[0xff, 0xfe]).is_err());
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::{BTreeMap,HashMap};
+use std::sync::{Arc,Mutex,Weak,mpsc};
+use std::sync::atomic::{AtomicU64,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::client::Client;
+use super::session::{SessionEvent,SessionState};
+
+use crate::result::OffkvError;
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// Cache settings, see `CachedClient::new`.
+#[derive(Clone, Debug)]
+pub struct CacheOptions {
+    /// maximal number of cached keys, the least recently used ones are evicted
+    pub capacity: usize,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions{
+            capacity: 1024,
+        }
+    }
+}
+
+/// Counters of a `CachedClient`, see `CachedClient::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// reads served from the cache
+    pub hits: u64,
+
+    /// reads that went to the store and cached the value
+    pub misses: u64,
+
+    /// reads that went to the store without caching the value: since the session
+    /// was suspended, or since too many watches were pending (see `CachedClient`)
+    pub bypassed: u64,
+
+    /// entries dropped since their keys changed
+    pub invalidations: u64,
+
+    /// entries dropped to stay within the capacity
+    pub evictions: u64,
+
+    /// number of keys cached at the moment
+    pub entries: usize,
+}
+
+/// Read-through cache of values and versions.
+///
+/// A missed key is read with a watch, and the entry lives until the watch reports
+/// a change of the key (or the key is evicted). Until then reads are served locally,
+/// so a read may return a value a moment older than the store's one, but never one
+/// older than the value the watch was set on. The watches can't be trusted once
+/// the session is suspended or replaced: the cache is cleared on those session events,
+/// and while the session is suspended reads go to the store directly (usually failing
+/// with `ConnectionLost` until the client reconnects).
+///
+/// A key has at most one pending watch. An evicted or invalidated entry stops being
+/// guarded by its watch, but liboffkv can't cancel a watch, so the watch stays pending
+/// until the key changes (or the session is closed), and a key read again meanwhile is
+/// cached under it. At most `capacity` such watches are kept pending: past that, keys
+/// without a pending watch are read from the store and not cached (see `CacheStats::bypassed`)
+/// until some of the watched keys change. Concurrent misses of a key share one
+/// read. Absent keys are not cached.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::client::{CacheOptions,CachedClient};
+/// use std::{thread,time::Duration};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let another_client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let version = client.create("/flags", "dark-mode=off", false).unwrap();
+/// let cached = CachedClient::new(&client, CacheOptions{capacity: 2});
+///
+/// for _ in 0..1000 {
+///     assert_eq!(cached.get("/flags").unwrap(), (version, String::from("dark-mode=off")));
+/// }
+/// assert_eq!((cached.stats().misses, cached.stats().hits), (1, 999));
+///
+/// // a change by anyone invalidates the entry
+/// let version = another_client.set("/flags", "dark-mode=on").unwrap();
+/// for _ in 0..50 {
+///     if cached.stats().invalidations == 1 {
+///         break;
+///     }
+///     thread::sleep(Duration::from_millis(100));
+/// }
+/// assert_eq!(cached.get("/flags").unwrap(), (version, String::from("dark-mode=on")));
+///
+/// // writes through the cache are visible at once
+/// let version = cached.set("/flags", "dark-mode=auto").unwrap();
+/// assert_eq!(cached.get("/flags").unwrap(), (version, String::from("dark-mode=auto")));
+///
+/// # client.erase("/flags", 0);
+/// ```
+#[derive(Clone)]
+pub struct CachedClient {
+    client: Client,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    capacity: usize,
+    state: Mutex<State>,
+    // the watches report to the thread following them
+    changes: mpsc::Sender<Signal>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bypassed: AtomicU64,
+    invalidations: AtomicU64,
+    evictions: AtomicU64,
+}
+
+struct Entry {
+    // tells the entry from the later ones of the same key
+    id: u64,
+    version: i64,
+    value: Vec<u8>,
+    last_used: u64,
+}
+
+// a pending watch of a key
+struct Watch {
+    // tells the watch from the later ones of the same key
+    id: u64,
+    // the version the watch was set on
+    version: i64,
+    // the id of the entry it guards
+    entry: u64,
+}
+
+enum Signal {
+    // the watch of the key with the id fired
+    Changed(String, u64),
+    Stop,
+}
+
+// readers waiting for a key being read, `None` if the read failed
+type Waiters = Vec<mpsc::Sender<Option<(i64, Vec<u8>)>>>;
+
+#[derive(Default)]
+struct State {
+    lru: Lru,
+    // keys being read, with the other readers of the key
+    loading: HashMap<String, Waiters>,
+    // keys with a pending watch
+    watched: HashMap<String, Watch>,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    // keys by the moment they were last used
+    order: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Lru {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn get(&mut self, key: &str) -> Option<(i64, Vec<u8>)> {
+        let now = self.tick();
+        let entry = self.entries.get_mut(key)?;
+
+        self.order.remove(&entry.last_used);
+        entry.last_used = now;
+        self.order.insert(now, String::from(key));
+        Some((entry.version, entry.value.clone()))
+    }
+
+    /// Returns the id of the entry and the number of entries evicted.
+    fn insert(&mut self, key: &str, version: i64, value: Vec<u8>, capacity: usize) -> (u64, u64) {
+        self.remove(key, None);
+
+        let mut evicted = 0;
+        while self.entries.len() >= capacity.max(1) {
+            let (_, oldest) = self.order.pop_first().unwrap();
+            self.entries.remove(&oldest);
+            evicted += 1;
+        }
+
+        let id = self.tick();
+        self.entries.insert(String::from(key), Entry{id, version, value, last_used: id});
+        self.order.insert(id, String::from(key));
+        (id, evicted)
+    }
+
+    /// Removes the entry of the key, only if its id matches if one is given.
+    fn remove(&mut self, key: &str, id: Option<u64>) -> bool {
+        match self.entries.get(key) {
+            Some(entry) if id.is_none_or(|id| id == entry.id) => {
+                self.order.remove(&entry.last_used);
+                self.entries.remove(key);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+impl CachedClient {
+    /// Creates an empty cache in front of the client.
+    pub fn new(client: &Client, options: CacheOptions) -> Self {
+        let (changes, changed) = mpsc::channel();
+        let shared = Arc::new(Shared{
+            capacity: options.capacity,
+            state: Mutex::new(State::default()),
+            changes,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            bypassed: AtomicU64::new(0),
+            invalidations: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        });
+
+        let (watcher, events) = (Arc::downgrade(&shared), client.session_events());
+        thread::Builder::new()
+            .name(String::from("rsoffkv-cache-session"))
+            .spawn(move || Shared::follow_session(watcher, events))
+            .expect("Failed to spawn cache session watcher");
+
+        let watcher = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name(String::from("rsoffkv-cache"))
+            .spawn(move || Shared::follow_watches(watcher, changed))
+            .expect("Failed to spawn cache watcher");
+
+        CachedClient{client: client.clone(), shared}
+    }
+
+    /// Returns the underlying client.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Same as `Client::get` without a watch, served from the cache if possible.
+    pub fn get(&self, key: &str) -> Result<(i64, String)> {
+        let (version, value) = self.get_bytes(key)?;
+        match String::from_utf8(value) {
+            Ok(value) => Ok((version, value)),
+            Err(error) => Err(OffkvError::Decode(error.to_string())),
+        }
+    }
+
+    /// Same as `Client::get_bytes` without a watch, served from the cache if possible.
+    pub fn get_bytes(&self, key: &str) -> Result<(i64, Vec<u8>)> {
+        if self.client.session_state() == SessionState::Suspended {
+            self.shared.state.lock().unwrap().lru.clear();
+            self.shared.bypassed.fetch_add(1, Ordering::Relaxed);
+            return self.read(key);
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(cached) = state.lru.get(key) {
+            self.shared.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(cached);
+        }
+        if !state.watched.contains_key(key) && state.watched.len() >= self.shared.max_watches() {
+            drop(state);
+            self.shared.bypassed.fetch_add(1, Ordering::Relaxed);
+            return self.read(key);
+        }
+        self.shared.misses.fetch_add(1, Ordering::Relaxed);
+
+        // another reader is on it
+        if let Some(waiters) = state.loading.get_mut(key) {
+            let (sender, receiver) = mpsc::channel();
+            waiters.push(sender);
+            drop(state);
+
+            return match receiver.recv() {
+                Ok(Some(loaded)) => Ok(loaded),
+                // its read failed, try on our own
+                _ => self.read(key),
+            };
+        }
+
+        state.loading.insert(String::from(key), Vec::new());
+        let watched = state.watched.contains_key(key);
+        drop(state);
+
+        match watched {
+            true => self.reload(key),
+            false => self.load(key),
+        }
+    }
+
+    /// Same as `Client::set`, drops the cached value of the key.
+    pub fn set<V: AsRef<[u8]>>(&self, key: &str, value: V) -> Result<i64> {
+        let result = self.client.set(key, value);
+        self.invalidate(key);
+        result
+    }
+
+    /// Same as `Client::cas`, drops the cached value of the key.
+    pub fn cas<V: AsRef<[u8]>>(&self, key: &str, value: V, version: i64) -> Result<i64> {
+        let result = self.client.cas(key, value, version);
+        self.invalidate(key);
+        result
+    }
+
+    /// Same as `Client::erase`, drops the cached value of the key.
+    pub fn erase(&self, key: &str, version: i64) -> Result<()> {
+        let result = self.client.erase(key, version);
+        self.invalidate(key);
+        result
+    }
+
+    /// Drops the cached value of the key.
+    pub fn invalidate(&self, key: &str) {
+        self.shared.state.lock().unwrap().lru.remove(key, None);
+    }
+
+    /// Drops all the cached values.
+    pub fn clear(&self) {
+        self.shared.state.lock().unwrap().lru.clear();
+    }
+
+    /// Returns the counters, shared by the clones of the client.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats{
+            hits: self.shared.hits.load(Ordering::Relaxed),
+            misses: self.shared.misses.load(Ordering::Relaxed),
+            bypassed: self.shared.bypassed.load(Ordering::Relaxed),
+            invalidations: self.shared.invalidations.load(Ordering::Relaxed),
+            evictions: self.shared.evictions.load(Ordering::Relaxed),
+            entries: self.shared.state.lock().unwrap().lru.entries.len(),
+        }
+    }
+
+    /// Reads the key bypassing the cache.
+    fn read(&self, key: &str) -> Result<(i64, Vec<u8>)> {
+        self.client.get_bytes(key, false).map(|(version, value, _)| (version, value))
+    }
+
+    /// Reads the key with a watch and caches it until it changes.
+    fn load(&self, key: &str) -> Result<(i64, Vec<u8>)> {
+        let read = self.client.get_bytes(key, true);
+
+        let mut state = self.shared.state.lock().unwrap();
+        match read {
+            Ok((version, value, watch_handle)) => {
+                if let Some(watch_handle) = watch_handle {
+                    let entry = self.shared.cache(&mut state, key, version, value.clone());
+                    let id = state.lru.tick();
+                    state.watched.insert(String::from(key), Watch{id, version, entry});
+
+                    let changed = || Signal::Changed(String::from(key), id);
+                    watch_handle.notify(self.shared.changes.clone(), changed(), changed());
+                }
+                Shared::loaded(&mut state, key, Some(&(version, value.clone())));
+                Ok((version, value))
+            },
+            Err(error) => {
+                Shared::loaded(&mut state, key, None);
+                Err(error)
+            },
+        }
+    }
+
+    /// Reads the key without a watch, caching it if the pending watch of the key was set on the same version.
+    fn reload(&self, key: &str) -> Result<(i64, Vec<u8>)> {
+        let read = self.read(key);
+
+        let mut state = self.shared.state.lock().unwrap();
+        if let Ok((version, value)) = &read {
+            if state.watched.get(key).is_some_and(|watch| watch.version == *version) {
+                let entry = self.shared.cache(&mut state, key, *version, value.clone());
+                state.watched.get_mut(key).unwrap().entry = entry;
+            }
+        }
+        Shared::loaded(&mut state, key, read.as_ref().ok());
+        read
+    }
+}
+
+impl Shared {
+    /// Maximal number of pending watches: one per entry and as many not guarding any.
+    fn max_watches(&self) -> usize {
+        self.capacity.max(1).saturating_mul(2)
+    }
+
+    /// Caches the value, returns the id of the entry.
+    fn cache(&self, state: &mut State, key: &str, version: i64, value: Vec<u8>) -> u64 {
+        let (id, evicted) = state.lru.insert(key, version, value, self.capacity);
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+        id
+    }
+
+    /// Ends the read of the key, handing its result to the other readers.
+    fn loaded(state: &mut State, key: &str, loaded: Option<&(i64, Vec<u8>)>) {
+        for waiter in state.loading.remove(key).unwrap_or_default() {
+            let _ = waiter.send(loaded.cloned());
+        }
+    }
+
+    /// Drops the entries whose keys changed.
+    fn follow_watches(shared: Weak<Shared>, changes: mpsc::Receiver<Signal>) {
+        loop {
+            let signal = changes.recv_timeout(Duration::from_secs(1));
+
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+
+            match signal {
+                Ok(Signal::Changed(key, id)) => {
+                    let mut state = shared.state.lock().unwrap();
+                    if let Some(watch) = state.watched.remove(&key) {
+                        if watch.id != id {
+                            state.watched.insert(key, watch);
+                        } else if state.lru.remove(&key, Some(watch.entry)) {
+                            shared.invalidations.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                },
+                Ok(Signal::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                Err(mpsc::RecvTimeoutError::Timeout) => {},
+            }
+        }
+    }
+
+    /// Clears the cache when the watches can't be trusted anymore.
+    fn follow_session(shared: Weak<Shared>, events: mpsc::Receiver<SessionEvent>) {
+        loop {
+            let event = events.recv_timeout(Duration::from_secs(1));
+
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+
+            match event {
+                Ok(SessionEvent::Suspended) | Ok(SessionEvent::Expired) | Ok(SessionEvent::Reconnected) =>
+                    shared.state.lock().unwrap().lru.clear(),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                _ => {},
+            }
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // the pending watches report to nobody from now on
+        let _ = self.changes.send(Signal::Stop);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(lru: &Lru) -> Vec<&str> {
+        lru.order.values().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let mut lru = Lru::default();
+        assert_eq!(lru.insert("/a", 1, b"a".to_vec(), 2).1, 0);
+        assert_eq!(lru.insert("/b", 1, b"b".to_vec(), 2).1, 0);
+
+        // "/a" becomes the most recently used one
+        assert_eq!(lru.get("/a"), Some((1, b"a".to_vec())));
+        assert_eq!(lru.insert("/c", 1, b"c".to_vec(), 2).1, 1);
+
+        assert_eq!(keys(&lru), vec!["/a", "/c"]);
+        assert_eq!(lru.get("/b"), None);
+    }
+
+    #[test]
+    fn reinserting_a_key_replaces_its_entry() {
+        let mut lru = Lru::default();
+        let (first, _) = lru.insert("/a", 1, b"old".to_vec(), 2);
+        let (second, evicted) = lru.insert("/a", 2, b"new".to_vec(), 2);
+
+        assert_ne!(first, second);
+        assert_eq!(evicted, 0);
+        assert_eq!(lru.entries.len(), 1);
+        assert_eq!(lru.get("/a"), Some((2, b"new".to_vec())));
+    }
+
+    #[test]
+    fn removal_by_id_spares_newer_entries() {
+        let mut lru = Lru::default();
+        let (old, _) = lru.insert("/a", 1, b"old".to_vec(), 2);
+        let (new, _) = lru.insert("/a", 2, b"new".to_vec(), 2);
+
+        assert!(!lru.remove("/a", Some(old)));
+        assert_eq!(lru.get("/a"), Some((2, b"new".to_vec())));
+        assert!(lru.remove("/a", Some(new)));
+        assert!(!lru.remove("/a", None));
+        assert!(lru.order.is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_keeps_one_entry() {
+        let mut lru = Lru::default();
+        lru.insert("/a", 1, Vec::new(), 0);
+        assert_eq!(lru.insert("/b", 1, Vec::new(), 0).1, 1);
+        assert_eq!(keys(&lru), vec!["/b"]);
+
+        lru.clear();
+        assert!(lru.entries.is_empty() && lru.order.is_empty());
+    }
+}
@@ -1,9 +1,15 @@
 use std::ffi::CString;
+use libc::c_int;
 use std::os::raw::{c_char,c_void};
-use std::{mem,ptr,slice};
+use std::sync::{Arc,mpsc};
+use std::time::{Duration,Instant};
+use std::{mem,ptr,slice,thread};
 
+use super::compression;
 use super::ffi::*;
+use super::session::*;
 
+use crate::retry::RetryPolicy;
 use crate::txn::*;
 use crate::result::*;
 
@@ -11,35 +17,139 @@ use crate::result::*;
 type Result<T> = std::result::Result<T, OffkvError>;
 
 
+/// Name of the child holding the last sequence number, see `Client::create_sequential`.
+pub(crate) const SEQUENCE_COUNTER: &str = ".seq";
+
+
+/// Owned liboffkv watch handle, dropped on drop.
+struct RawWatch(*mut c_void);
+
+// a watch may be waited for on any thread
+unsafe impl Send for RawWatch {}
+
+impl RawWatch {
+    fn new(ffi_watch_handle: *mut c_void) -> Option<Self> {
+        match ffi_watch_handle.is_null() {
+            true => None,
+            false => Some(RawWatch(ffi_watch_handle)),
+        }
+    }
+}
+
+impl Drop for RawWatch {
+    fn drop(&mut self) {
+        unsafe {
+            offkv_watch_drop(self.0);
+        }
+    }
+}
+
+
+/// What ended waiting on a `WatchHandle`, see `WatchHandle::wait`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// the events the watch was set for occurred
+    Changed,
+
+    /// the session the watch was set in has been replaced by a new one
+    /// (see `SessionEvent::Expired`), the watch won't fire anymore
+    SessionReplaced,
+}
+
 pub struct WatchHandle<'a> {
     _parent_client: &'a Client,
-    _offkv_watch_handle: *mut c_void,
+    _offkv_watch_handle: RawWatch,
+    // keeps the session the watch was set in open
+    _offkv_handle: Arc<Handle>,
+    generation: u64,
 }
 
 impl<'a> WatchHandle<'a> {
     /// Waits until some events occurred (depends on method `WatchHandle` is returned from)
-    pub fn wait(self) {
-        unsafe {
-            offkv_watch(self._offkv_watch_handle);
-        }
+    ///
+    /// If the session the watch was set in is replaced by a new one, before or
+    /// during the wait, returns `WatchEvent::SessionReplaced`: changes made while
+    /// the client was disconnected can't be observed, so the state should be re-read
+    /// and the watch set again.
+    pub fn wait(self) -> WatchEvent {
+        let (sender, receiver) = mpsc::channel();
+        self.notify(sender, WatchEvent::Changed, WatchEvent::SessionReplaced);
+        receiver.recv().unwrap_or(WatchEvent::SessionReplaced)
     }
 
-    pub(crate) fn new(parent: &'a Client, ffi_watch_handle: *mut c_void) -> Self {
-        Self{_parent_client: parent, _offkv_watch_handle: ffi_watch_handle}
+    /// Waits until some events occurred or the timeout elapsed.
+    ///
+    /// On timeout the watch stays armed on a helper thread until it fires
+    /// (or the client is dropped).
+    ///
+    /// # Returns:
+    ///
+    /// * `true` if the events occurred or the session was replaced (see `wait`),
+    ///   `false` on timeout
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// use std::time::Duration;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    /// client.set("/key", "value").unwrap();
+    ///
+    /// let (_, watch_handle) = client.exists("/key", true).unwrap();
+    /// // nobody touches the key
+    /// assert!(!watch_handle.unwrap().wait_timeout(Duration::from_millis(100)));
+    ///
+    /// # client.erase("/key", 0);
+    /// ```
+    pub fn wait_timeout(self, timeout: Duration) -> bool {
+        let (sender, receiver) = mpsc::channel();
+        self.notify(sender, (), ());
+        receiver.recv_timeout(timeout).is_ok()
     }
-}
 
-impl Drop for WatchHandle<'_> {
-    fn drop(&mut self) {
-        unsafe {
-            offkv_watch_drop(self._offkv_watch_handle);
+    /// Sends `changed` once the events occur, or `replaced` once the session the watch
+    /// was set in is replaced, whichever comes first. The watch is waited for
+    /// on a helper thread.
+    pub(crate) fn notify<T: Send + 'static>(self, sender: mpsc::Sender<T>, changed: T, replaced: T) {
+        let WatchHandle{_parent_client: client, _offkv_watch_handle: watch_handle, _offkv_handle: offkv_handle, generation} = self;
+
+        let on_replaced = sender.clone();
+        let id = match client.session.on_replaced(generation, Box::new(move || { let _ = on_replaced.send(replaced); })) {
+            Some(id) => id,
+            None => return,
+        };
+
+        let session = Arc::downgrade(&client.session);
+        thread::Builder::new()
+            .name(String::from("rsoffkv-watch"))
+            .spawn(move || {
+                let _offkv_handle = offkv_handle;
+                unsafe {
+                    offkv_watch(watch_handle.0);
+                }
+                // unless the session has been replaced meanwhile
+                if session.upgrade().is_some_and(|session| session.cancel_replaced(id)) {
+                    let _ = sender.send(changed);
+                }
+            })
+            .expect("Failed to spawn watching thread");
+    }
+
+    fn new(parent: &'a Client, watch_handle: RawWatch,
+           offkv_handle: Arc<Handle>, generation: u64) -> Self {
+        Self{
+            _parent_client: parent,
+            _offkv_watch_handle: watch_handle,
+            _offkv_handle: offkv_handle,
+            generation,
         }
     }
 }
 
 
+#[derive(Clone)]
 pub struct Client {
-    offkv_handle: *mut c_void,
+    session: Arc<Session>,
+    deadline: Option<Duration>,
 }
 
 
@@ -50,7 +160,7 @@ impl Client {
     /// # Arguments:
     ///
     /// * `url` - Address, where the service is located. Must be of form
-    /// `<service_name>://<host>:<port>` where `<service_name>` is one of `{zk, consul, etcd}`.
+    ///   `<service_name>://<host>:<port>` where `<service_name>` is one of `{zk, consul, etcd}`.
     /// * `prefix` - An additional prefix, all used keys start with.
     ///
     /// # Example:
@@ -62,19 +172,102 @@ impl Client {
     /// let etcd_client = Client::new("etcd://localhost:2379", "/test_prefix").unwrap();
     /// ```
     pub fn new(url: &str, prefix: &str) -> Result<Self> {
-        let mut error_code: i32 = 0;
-
-        let offkv_handle: *mut c_void = unsafe {
-            offkv_open(
-                // create a null-terminated owned string
-                // it will be live until the function returns so ptr will be valid
-                to_cstring(url).as_ptr(),
-                to_cstring(prefix).as_ptr(),
-                &mut error_code,
-            )
-        };
+        Self::with_options(url, prefix, ClientOptions::default())
+    }
 
-        from_error_code(error_code as i64).map_or(Ok(Client{offkv_handle}), Err)
+    /// Creates a new client with non-default settings.
+    ///
+    /// # Arguments:
+    ///
+    /// * `url` - Address, where the service is located (see `Client::new`).
+    /// * `prefix` - An additional prefix, all used keys start with.
+    /// * `options` - client settings
+    ///
+    /// # Example:
+    ///
+    /// ```
+    /// use rsoffkv::client::{Client, ClientOptions};
+    /// use rsoffkv::backoff::Backoff;
+    /// use std::time::Duration;
+    /// let client = Client::with_options("consul://localhost:8500", "/test_prefix", ClientOptions{
+    ///     // notice connection losses while idle and reopen the session after them
+    ///     heartbeat: Some(Duration::from_secs(1)),
+    ///     reconnect: Some(Backoff::default()),
+    ///     // create leased keys again after the client reconnects
+    ///     recreate_leased: true,
+    ///     ..ClientOptions::default()
+    /// }).unwrap();
+    /// ```
+    pub fn with_options(url: &str, prefix: &str, options: ClientOptions) -> Result<Self> {
+        let deadline = options.default_deadline;
+        Ok(Client{session: Session::open(url, prefix, options)?, deadline})
+    }
+
+    /// Returns a client that shares the session with this one but gives up
+    /// operations that take longer than the deadline with `OffkvError::Timeout`.
+    ///
+    /// The deadline covers the whole operation including retries (see `ClientOptions::retry`)
+    /// and watch registration, but not waiting on the returned `WatchHandle`.
+    /// An operation timed out may still be applied by the service.
+    ///
+    /// # Arguments:
+    ///
+    /// * `deadline` - time limit for each operation, `None` lifts the limit
+    ///   (including the default one, see `ClientOptions::default_deadline`)
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// use std::time::Duration;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    ///
+    /// let impatient = client.with_deadline(Duration::from_secs(1));
+    /// impatient.set("/key", "value").unwrap();
+    ///
+    /// # client.erase("/key", 0);
+    /// ```
+    pub fn with_deadline<D: Into<Option<Duration>>>(&self, deadline: D) -> Client {
+        Client{session: Arc::clone(&self.session), deadline: deadline.into()}
+    }
+
+    /// Subscribes to session lifecycle events.
+    ///
+    /// If the client is connected at the moment, `SessionEvent::Connected` is
+    /// delivered first. After a connection loss the client reports `Suspended`
+    /// and, if reconnection is enabled (see `ClientOptions::reconnect`), reopens
+    /// the session reporting `Expired` followed by `Reconnected`. Leased keys
+    /// of the old session are gone by then unless `ClientOptions::recreate_leased`
+    /// is set; watches set in the old session complete immediately.
+    ///
+    /// # Returns:
+    ///
+    /// * receiving end of a channel the events are sent to
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// use rsoffkv::client::SessionEvent;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    /// let events = client.session_events();
+    ///
+    /// assert_eq!(events.recv().unwrap(), SessionEvent::Connected);
+    /// ```
+    pub fn session_events(&self) -> mpsc::Receiver<SessionEvent> {
+        self.session.subscribe()
+    }
+
+    /// Returns the current state of the session.
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// use rsoffkv::client::SessionState;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    ///
+    /// assert_eq!(client.session_state(), SessionState::Connected);
+    /// ```
+    pub fn session_state(&self) -> SessionState {
+        self.session.state()
     }
 
     /// Creates new key. The parent key must exist.
@@ -100,22 +293,19 @@ impl Client {
     ///
     /// # client.erase("/key", 0);
     /// ```
-    pub fn create(&self, key: &str, value: &str, leased: bool) -> Result<i64> {
-        let result = unsafe {
-            offkv_create(
-                self.offkv_handle,
-                to_cstring(key).as_ptr(),
-                // not null-terminated
-                value.as_ptr() as *const c_char,
-                value.len(),
-                match leased {
-                    true => OFFKV_LEASE,
-                    false => 0,
-                }
-            )
-        };
+    pub fn create<V: AsRef<[u8]>>(&self, key: &str, value: V, leased: bool) -> Result<i64> {
+        let value = value.as_ref();
+        let deadline = self.deadline();
+        let result = self.retrying(deadline, |attempt| match self.create_once(key, value, leased, deadline) {
+            // the previous attempt may have created the key itself
+            Err(OffkvError::EntryExists) if attempt > 0 => self.written_version(key, value, deadline),
+            result => result,
+        });
 
-        from_error_code(result).map_or(Ok(result), Err)
+        if let (true, Ok(version)) = (leased, &result) {
+            self.session.remember_leased(key, value, *version);
+        }
+        result
     }
 
     /// Erases existing key.
@@ -124,8 +314,8 @@ impl Client {
     ///
     /// * `key` - key to erase
     /// * `version` - if not 0, erases the key _and all its descendants_
-    /// iff its version equals to the given one,
-    /// otherwise does it unconditionally
+    ///   iff its version equals to the given one,
+    ///   otherwise does it unconditionally
     ///
     /// # Returns:
     /// * ()
@@ -150,15 +340,18 @@ impl Client {
     /// # client.erase("/key", 0);
     /// ```
     pub fn erase(&self, key: &str, version: i64) -> Result<()> {
-        let result = unsafe {
-            offkv_erase(
-                self.offkv_handle,
-                to_cstring(key).as_ptr(),
-                version,
-            )
-        };
+        let deadline = self.deadline();
+        let result = self.retrying(deadline, |attempt| match self.erase_once(key, version, deadline) {
+            // the key is gone whether or not the previous attempt erased it
+            Err(OffkvError::NoEntry) if attempt > 0 => Ok(()),
+            result => result,
+        });
 
-        from_error_code(result as i64).map_or(Ok(()), Err)
+        // a conditional erase may have done nothing
+        if result.is_ok() && (version == 0 || matches!(self.exists_once(key, false, deadline), Ok((0, _)))) {
+            self.session.forget_leased(key);
+        }
+        result
     }
 
     /// Assigns the value to the the key, creates it not exist (the parent key must exist).
@@ -195,17 +388,17 @@ impl Client {
     ///
     /// # client.erase("/key", 0);
     /// ```
-    pub fn set(&self, key: &str, value: &str) -> Result<i64> {
-        let result = unsafe {
-            offkv_set(
-                self.offkv_handle,
-                to_cstring(key).as_ptr(),
-                value.as_ptr() as *const c_char,
-                value.len(),
-            )
-        };
+    pub fn set<V: AsRef<[u8]>>(&self, key: &str, value: V) -> Result<i64> {
+        let value = value.as_ref();
+        let deadline = self.deadline();
+        // a service error is the service's answer, e.g. to a value that is too large
+        let result = self.retrying_on(deadline, |error| matches!(error, OffkvError::ConnectionLost),
+            |_| self.set_once(key, value, deadline));
 
-        from_error_code(result).map_or(Ok(result), Err)
+        if let Ok(version) = result {
+            self.session.refresh_leased(key, value, version);
+        }
+        result
     }
 
     /// Compare and set operation: if version is not 0, assigns value to key iff
@@ -254,18 +447,21 @@ impl Client {
     ///
     /// # client.erase("/key", 0);
     /// ```
-    pub fn cas(&self, key: &str, value: &str, version: i64) -> Result<i64> {
-        let result = unsafe {
-            offkv_cas(
-                self.offkv_handle,
-                to_cstring(key).as_ptr(),
-                value.as_ptr() as *const c_char,
-                value.len(),
-                version,
-            )
-        };
+    pub fn cas<V: AsRef<[u8]>>(&self, key: &str, value: V, version: i64) -> Result<i64> {
+        let value = value.as_ref();
+        let deadline = self.deadline();
+        let result = self.retrying(deadline, |attempt| match self.cas_once(key, value, version, deadline) {
+            // the previous attempt may have changed the version itself
+            Ok(0) if attempt > 0 => self.written_version(key, value, deadline),
+            result => result,
+        });
 
-        from_error_code(result).map_or(Ok(result), Err)
+        if let Ok(new_version) = result {
+            if new_version != 0 {
+                self.session.refresh_leased(key, value, new_version);
+            }
+        }
+        result
     }
 
     /// Returns current version and assigned value.
@@ -274,13 +470,14 @@ impl Client {
     ///
     /// * `key` - a certain key
     /// * `watch` - if true, a `WatchHandle` is returned,
-    /// it can wait for key deletion or its value change.
+    ///   it can wait for key deletion or its value change.
     ///
     /// # Returns:
     ///
     /// * current version of the key
     /// * current assigned value
     /// * (optional) `WatchHandle`
+    /// * `OffkvError::Decode` if the value is not valid UTF-8
     ///
     /// # Example:
     /// ```
@@ -309,38 +506,223 @@ impl Client {
     /// # client.erase("/key", 0);
     /// ```
     pub fn get(&self, key: &str, watch: bool)
-           -> Result<(i64, String, Option<WatchHandle>)> {
+           -> Result<(i64, String, Option<WatchHandle<'_>>)> {
 
-        let mut watch_handle: *mut c_void = match watch {
-            true => ptr::NonNull::dangling().as_ptr(),
-            false => ptr::null_mut(),
-        };
+        let (version, value, watch_handle) = self.get_bytes(key, watch)?;
 
-        let offkv_GetResult{version, value, value_size} = unsafe {
-            offkv_get(
-                self.offkv_handle,
-                to_cstring(key).as_ptr(),
-                &mut watch_handle,
-            )
-        };
+        // `get_bytes` is for values that aren't UTF-8
+        match String::from_utf8(value) {
+            Ok(value) => Ok((version, value, watch_handle)),
+            Err(error) => Err(OffkvError::Decode(error.to_string())),
+        }
+    }
 
-        if let Some(error) = from_error_code(version) {
-            Err(error)
-        } else {
-            // <str_value> now _owns_ the data <value> is pointing at
-            // so on its destroy the data will be freed
-            let str_value = unsafe {
-                String::from_raw_parts(value as *mut u8, value_size, value_size)
+    /// Same as `get` but returns the value as raw bytes, use it for non UTF-8 values.
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    /// client.set("/key", [0u8, 159, 146, 150]).unwrap();
+    ///
+    /// let (_, value, _) = client.get_bytes("/key", false).unwrap();
+    /// assert_eq!(value, vec![0u8, 159, 146, 150]);
+    ///
+    /// # client.erase("/key", 0);
+    /// ```
+    pub fn get_bytes(&self, key: &str, watch: bool)
+           -> Result<(i64, Vec<u8>, Option<WatchHandle<'_>>)> {
+
+        let deadline = self.deadline();
+        self.retrying(deadline, |_| self.get_once(key, watch, deadline))
+    }
+
+    /// Atomically replaces the value of the key with the one computed from the current value:
+    /// reads the key, calls `f` and writes the result with `cas`, starting over if the key
+    /// has been changed meanwhile. Conflicts are retried according to `ClientOptions::conflicts`.
+    ///
+    /// `f` may be called several times so it must not have side effects.
+    ///
+    /// # Arguments:
+    ///
+    /// * `key` - key to update
+    /// * `f` - gets the current value (`None` if the key doesn't exist) and
+    ///   returns the new one (`None` to erase the key)
+    ///
+    /// # Returns:
+    ///
+    /// * new version of the key (0 if the key was erased or left absent)
+    /// * `OffkvError::TxnFailed` if the conflicts persisted
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    ///
+    /// let increment = |old: Option<&[u8]>| {
+    ///     let old: u64 = old.map_or(0, |old| String::from_utf8_lossy(old).parse().unwrap());
+    ///     Some((old + 1).to_string().into_bytes())
+    /// };
+    ///
+    /// // creates the key
+    /// client.update("/counter", increment).unwrap();
+    /// let version = client.update("/counter", increment).unwrap();
+    ///
+    /// let (current_version, value, _) = client.get("/counter", false).unwrap();
+    /// assert_eq!(current_version, version);
+    /// assert_eq!(value, String::from("2"));
+    ///
+    /// // erases the key
+    /// assert_eq!(client.update("/counter", |_| None).unwrap(), 0);
+    /// assert_eq!(client.exists("/counter", false).unwrap().0, 0);
+    /// ```
+    pub fn update<F>(&self, key: &str, mut f: F) -> Result<i64>
+        where F: FnMut(Option<&[u8]>) -> Option<Vec<u8>> {
+
+        self.try_update(key, |old| Ok(f(old)))
+    }
+
+    /// Same as `update` but `f` may fail, which stops the update with the error.
+    pub(crate) fn try_update<F>(&self, key: &str, mut f: F) -> Result<i64>
+        where F: FnMut(Option<&[u8]>) -> Result<Option<Vec<u8>>> {
+
+        self.session.options().conflicts.run_optimistic(|_| {
+            let (version, old) = match self.get_bytes(key, false) {
+                Ok((version, value, _)) => (version, Some(value)),
+                Err(OffkvError::NoEntry) => (0, None),
+                Err(error) => return Err(error),
             };
 
-            let watch_handle = if !watch_handle.is_null() {
-                Some(WatchHandle::new(&self, watch_handle))
-            } else {
-                None
+            match (old.is_some(), f(old.as_deref())?) {
+                (false, None) => Ok(Some(0)),
+                (false, Some(new)) => match self.create(key, new, false) {
+                    Ok(version) => Ok(Some(version)),
+                    Err(OffkvError::EntryExists) => Ok(None),
+                    Err(error) => Err(error),
+                },
+                (true, Some(new)) => match self.cas(key, new, version) {
+                    Ok(0) | Err(OffkvError::NoEntry) => Ok(None),
+                    Ok(version) => Ok(Some(version)),
+                    Err(error) => Err(error),
+                },
+                (true, None) => match self.commit(Transaction{
+                    checks: vec![TxnCheck{key, version}],
+                    ops: vec![TxnOp::Erase{key}],
+                }) {
+                    Ok(_) => Ok(Some(0)),
+                    Err(OffkvError::TxnFailed(_)) => Ok(None),
+                    Err(error) => Err(error),
+                },
+            }
+        })
+    }
+
+    /// Creates new key with a unique name: the prefix followed by a zero-padded
+    /// sequence number, e.g. `/queue/item-0000000042`. The parent key must exist.
+    ///
+    /// The liboffkv C API exposes no sequential flag, so on every service (ZooKeeper
+    /// included) the last sequence number is kept in the counter key `<parent>/.seq`,
+    /// created on first use and left out by `get_children`: the key is created in a `commit`
+    /// together with the increment of the counter, which is retried according to `ClientOptions::conflicts`.
+    ///
+    /// Sequence numbers of keys created under the same parent are unique and grow
+    /// in the order the creations are applied, so they order concurrent creators;
+    /// numbers of keys created by a single client grow in the order of calls.
+    /// Numbers are never reused, but there are gaps once keys are erased.
+    /// The padding makes lexicographical order of the names match the numeric one.
+    ///
+    /// # Arguments:
+    ///
+    /// * `prefix` - full path of the key without the sequence number
+    /// * `value` - initial value
+    /// * `leased` - if `true` the key will be removed on client's disconnect
+    ///
+    /// # Returns:
+    ///
+    /// * the created key
+    /// * `OffkvError::Decode` if the counter key holds something else than a number
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// use std::thread;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    /// client.create("/jobs", "nightly", false).unwrap();
+    ///
+    /// let first = client.create_sequential("/jobs/job-", "build", false).unwrap();
+    /// let second = client.create_sequential("/jobs/job-", "deploy", false).unwrap();
+    /// assert_eq!(first, "/jobs/job-0000000001");
+    /// assert_eq!(second, "/jobs/job-0000000002");
+    ///
+    /// let (_, value, _) = client.get(&second, false).unwrap();
+    /// assert_eq!(value, String::from("deploy"));
+    ///
+    /// // the parent's value is left alone
+    /// assert_eq!(client.get("/jobs", false).unwrap().1, String::from("nightly"));
+    ///
+    /// // concurrent creators get distinct numbers, each one's growing
+    /// let creators: Vec<_> = (0..4).map(|_| {
+    ///     let client = client.clone();
+    ///     thread::spawn(move || (0..10)
+    ///         .map(|_| client.create_sequential("/jobs/job-", "", false).unwrap())
+    ///         .collect::<Vec<_>>())
+    /// }).collect();
+    ///
+    /// let mut created = Vec::new();
+    /// for creator in creators {
+    ///     let keys = creator.join().unwrap();
+    ///     assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    ///     created.extend(keys);
+    /// }
+    /// created.sort();
+    /// created.dedup();
+    /// assert_eq!(created.len(), 40);
+    /// assert!(created[0] > second);
+    ///
+    /// # client.erase("/jobs", 0);
+    /// ```
+    pub fn create_sequential<V: AsRef<[u8]>>(&self, prefix: &str, value: V, leased: bool) -> Result<String> {
+        self.create_sequential_versioned(prefix, value.as_ref(), leased).map(|(key, _, _)| key)
+    }
+
+    /// Does `create_sequential`, also returning the version of the created key
+    /// and the version of the counter key after the increment.
+    pub(crate) fn create_sequential_versioned(&self, prefix: &str, value: &[u8], leased: bool)
+        -> Result<(String, i64, i64)> {
+
+        let parent = &prefix[..prefix.rfind('/').unwrap_or(0)];
+        let counter = format!("{}/{}", parent, SEQUENCE_COUNTER);
+
+        self.session.options().conflicts.run_optimistic(|_| {
+            let (version, last) = match self.get_bytes(&counter, false) {
+                Ok((version, last, _)) => match std::str::from_utf8(&last).ok().and_then(|last| last.parse::<u64>().ok()) {
+                    Some(last) => (version, last),
+                    None => return Err(OffkvError::Decode(format!("{} is not a sequence counter", counter))),
+                },
+                // the commit would fail the same way as on a conflict
+                Err(OffkvError::NoEntry) if !parent.is_empty() && self.exists(parent, false)?.0 == 0 =>
+                    return Err(OffkvError::NoEntry),
+                Err(OffkvError::NoEntry) => (0, 0),
+                Err(error) => return Err(error),
             };
+            let key = format!("{}{:010}", prefix, last + 1);
+            let next = (last + 1).to_string();
 
-            return Ok((version, str_value, watch_handle));
-        }
+            let (checks, increment) = match version {
+                0 => (vec![], TxnOp::create(&counter, &next, false)),
+                version => (vec![TxnCheck{key: &counter, version}], TxnOp::set(&counter, &next)),
+            };
+
+            match self.commit(Transaction{checks, ops: vec![increment, TxnOp::create(&key, value, leased)]}) {
+                Ok(results) => match results[..] {
+                    [TxnOpResult::Create(counter_version) | TxnOpResult::Set(counter_version), TxnOpResult::Create(version)] =>
+                        Ok(Some((key, version, counter_version))),
+                    _ => unreachable!(),
+                },
+                Err(OffkvError::TxnFailed(_)) => Ok(None),
+                Err(error) => Err(error),
+            }
+        })
     }
 
     /// Checks if the key exists.
@@ -379,38 +761,21 @@ impl Client {
     /// thread::sleep(time::Duration::from_secs(5));
     /// client.erase("/key", 0);
     /// ```
-    pub fn exists(&self, key: &str, watch: bool) -> Result<(i64, Option<WatchHandle>)> {
-        let mut watch_handle: *mut c_void = match watch {
-            true => ptr::NonNull::dangling().as_ptr(),
-            false => ptr::null_mut(),
-        };
-
-        let result = unsafe {
-            offkv_exists(
-                self.offkv_handle,
-                to_cstring(key).as_ptr(),
-                &mut watch_handle,
-            )
-        };
-
-        if let Some(error) = from_error_code(result) {
-            Err(error)
-        } else {
-            let watch_handle = if !watch_handle.is_null() {
-                Some(WatchHandle::new(&self, watch_handle))
-            } else { None };
-
-            Ok((result, watch_handle))
-        }
+    pub fn exists(&self, key: &str, watch: bool) -> Result<(i64, Option<WatchHandle<'_>>)> {
+        let deadline = self.deadline();
+        self.retrying(deadline, |_| self.exists_once(key, watch, deadline))
     }
 
     /// Returns a list of _direct_ children.
     ///
+    /// The counter kept by `create_sequential` under a parent (`<parent>/.seq`)
+    /// is not listed.
+    ///
     /// # Arguments:
     ///
     /// * `key` - key whose children are to be found
     /// * `watch` - if true, creates `WatchHandle`
-    /// that can wait for any changes among children of given key
+    ///   that can wait for any changes among children of given key
     ///
     /// # Returns:
     /// * `Vec` of direct children
@@ -467,40 +832,66 @@ impl Client {
     /// # client.erase("/key", 0);
     /// ```
     pub fn get_children(&self, key: &str, watch: bool)
-        -> Result<(Vec<String>, Option<WatchHandle>)> {
-
-        let mut watch_handle: *mut c_void = match watch {
-            true => ptr::NonNull::dangling().as_ptr(),
-            false => ptr::null_mut(),
-        };
+        -> Result<(Vec<String>, Option<WatchHandle<'_>>)> {
 
-        let offkv_ChildrenResult{keys, nkeys, error_code} = unsafe {
-            offkv_children(
-                self.offkv_handle,
-                to_cstring(key).as_ptr(),
-                &mut watch_handle,
-            )
-        };
+        let deadline = self.deadline();
+        self.retrying(deadline, |_| self.get_children_once(key, watch, deadline))
+    }
 
-        if let Some(error) = from_error_code(error_code as i64) {
-            Err(error)
-        } else {
-            let mut vec = Vec::new();
-            unsafe {
-                let keys = slice::from_raw_parts(keys, nkeys);
-                for key in keys {
-                    vec.push(CString::from_raw(*key).into_string().unwrap());
-                }
-            }
+    /// Runs an optimistic multi-key transaction: `f` reads keys and buffers writes
+    /// through the given `TxnContext`, then the writes are committed atomically
+    /// provided that none of the keys read has changed. On conflict `f` is run again
+    /// (see `ClientOptions::conflicts`), so it must not have side effects.
+    ///
+    /// Reading a key that doesn't exist is only validated if the transaction writes the key
+    /// (the backends can't check for absence otherwise).
+    ///
+    /// # Arguments:
+    ///
+    /// * `f` - transaction body; an error returned from it aborts the transaction
+    ///
+    /// # Returns:
+    ///
+    /// * the value returned from `f` by the committed run
+    /// * `OffkvError::TxnFailed` if the conflicts persisted
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    /// client.set("/a", "10").unwrap();
+    /// client.set("/b", "0").unwrap();
+    ///
+    /// // move 3 units from /a to /b keeping the total
+    /// let moved = client.transact(|tx| {
+    ///     let a: i64 = String::from_utf8(tx.get("/a")?.unwrap()).unwrap().parse().unwrap();
+    ///     let b: i64 = String::from_utf8(tx.get("/b")?.unwrap()).unwrap().parse().unwrap();
+    ///     let amount = a.min(3);
+    ///
+    ///     tx.set("/a", (a - amount).to_string());
+    ///     tx.set("/b", (b + amount).to_string());
+    ///     Ok(amount)
+    /// }).unwrap();
+    ///
+    /// assert_eq!(moved, 3);
+    /// assert_eq!(client.get("/a", false).unwrap().1, String::from("7"));
+    /// assert_eq!(client.get("/b", false).unwrap().1, String::from("3"));
+    ///
+    /// # client.erase("/a", 0);
+    /// # client.erase("/b", 0);
+    /// ```
+    pub fn transact<T, F>(&self, mut f: F) -> Result<T>
+        where F: FnMut(&mut TxnContext) -> Result<T> {
 
-            let watch_handle = if !watch_handle.is_null() {
-                Some(WatchHandle::new(&self, watch_handle))
-            } else {
-                None
-            };
+        self.session.options().conflicts.run_optimistic(|_| {
+            let mut context = TxnContext::new(self);
+            let result = f(&mut context)?;
 
-            Ok((vec, watch_handle))
-        }
+            match context.commit()? {
+                true => Ok(Some(result)),
+                false => Ok(None),
+            }
+        })
     }
 
     /// Commits transaction. Transaction consists of two parts: firstly list
@@ -516,7 +907,7 @@ impl Client {
     /// # Rertuns:
     ///
     /// * `Vec` of TxnOpResult - for each operation affecting versions
-    /// (namely, `TxnOp::Set` and `TxnOp::Create`) returns a new key version
+    ///   (namely, `TxnOp::Set` and `TxnOp::Create`) returns a new key version
     ///
     /// # Example:
     /// ```
@@ -530,8 +921,8 @@ impl Client {
     ///         TxnCheck{key: "/key", version: initial_version},
     ///     ],
     ///     ops: vec![
-    ///         TxnOp::Create{key: "/key/child", value: "value", leased: false},
-    ///         TxnOp::Set{key: "/key", value: "new value"},
+    ///         TxnOp::create("/key/child", "value", false),
+    ///         TxnOp::set("/key", "new value"),
     ///     ],
     /// }).unwrap();
     ///
@@ -541,93 +932,381 @@ impl Client {
     /// # client.erase("/key", 0);
     /// ```
     pub fn commit(&self, transaction: Transaction) -> Result<Vec<TxnOpResult>> {
-        let mut checks = Vec::new();
+        let deadline = self.deadline();
+        let result = self.retrying(deadline, |attempt| match self.commit_once(&transaction, deadline) {
+            // the previous attempt may have been committed
+            Err(OffkvError::TxnFailed(_)) if attempt > 0 => self.committed_results(&transaction, deadline),
+            result => result,
+        });
+
+        if let Ok(results) = &result {
+            let mut versions = results.iter().map(|result| match *result {
+                TxnOpResult::Create(version) | TxnOpResult::Set(version) => version,
+            });
+            for op in transaction.ops.iter() {
+                match op.bytes() {
+                    BytesOp::Create{key, value, leased} => {
+                        let version = versions.next().unwrap_or(0);
+                        if leased {
+                            self.session.remember_leased(key, value, version);
+                        }
+                    },
+                    BytesOp::Set{key, value} => self.session.refresh_leased(key, value, versions.next().unwrap_or(0)),
+                    BytesOp::Erase{key} => self.session.forget_leased(key),
+                }
+            }
+        }
+        result
+    }
+}
+
+
+// Single attempts of the operations, `Client::retrying` runs them
+// according to the client's `RetryPolicy`.
+impl Client {
+    pub(crate) fn options(&self) -> &ClientOptions {
+        self.session.options()
+    }
+
+    /// Returns the moment the operation started now must be completed by.
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline.map(|timeout| Instant::now() + timeout)
+    }
+
+    fn retrying<T, F>(&self, deadline: Option<Instant>, attempt: F) -> Result<T>
+        where F: FnMut(u32) -> Result<T> {
+
+        self.retrying_on(deadline, RetryPolicy::is_transient, attempt)
+    }
+
+    /// Does `retrying`, retrying only the errors `transient` returns `true` for.
+    fn retrying_on<T, F, P>(&self, deadline: Option<Instant>, transient: P, mut attempt: F) -> Result<T>
+        where F: FnMut(u32) -> Result<T>, P: Fn(&OffkvError) -> bool {
+
+        match self.session.options().retry {
+            Some(ref policy) => {
+                let mut policy = policy.clone();
+                // don't start attempts that can't be completed in time
+                if let Some(deadline) = deadline {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    policy.deadline = Some(policy.deadline.map_or(remaining, |d| d.min(remaining)));
+                }
+                policy.run_on(transient, attempt)
+            },
+            None => attempt(0),
+        }
+    }
+
+    /// Resolves the outcome of a write retried after an attempt that may have been applied
+    /// by re-reading the key: returns its version if it holds the written value,
+    /// `OffkvError::OutcomeUnknown` otherwise.
+    fn written_version(&self, key: &str, value: &[u8], deadline: Option<Instant>) -> Result<i64> {
+        match self.get_once(key, false, deadline) {
+            Ok((version, current, _)) if current == value => Ok(version),
+            Ok(_) | Err(OffkvError::NoEntry) => Err(OffkvError::OutcomeUnknown),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Resolves the outcome of a transaction retried after an attempt that may have been
+    /// committed by re-reading the keys it writes: returns the results of the commit if
+    /// the written keys hold the written values and the erased ones are gone,
+    /// `OffkvError::OutcomeUnknown` otherwise.
+    fn committed_results(&self, transaction: &Transaction, deadline: Option<Instant>) -> Result<Vec<TxnOpResult>> {
+        let mut results = Vec::new();
+        for op in transaction.ops.iter() {
+            match op.bytes() {
+                BytesOp::Create{key, value, ..} =>
+                    results.push(TxnOpResult::Create(self.written_version(key, value, deadline)?)),
+                BytesOp::Set{key, value} =>
+                    results.push(TxnOpResult::Set(self.written_version(key, value, deadline)?)),
+                BytesOp::Erase{key} => if self.exists_once(key, false, deadline)?.0 != 0 {
+                    return Err(OffkvError::OutcomeUnknown);
+                },
+            }
+        }
+        Ok(results)
+    }
+
+    /// Returns the value to write, compressed according to the options.
+    fn encode_value(&self, value: &[u8]) -> Result<Vec<u8>> {
+        let options = self.session.options();
+        compression::compress(options.compression, options.compression_min_size, value)
+    }
+
+    fn create_once(&self, key: &str, value: &[u8], leased: bool, deadline: Option<Instant>) -> Result<i64> {
+        let (handle, generation) = self.session.handle();
+        let (key, value) = (to_cstring(key), self.encode_value(value)?);
+
+        let result = self.session.call(deadline, move || unsafe {
+            offkv_create(
+                handle.0,
+                key.as_ptr(),
+                // not null-terminated
+                value.as_ptr() as *const c_char,
+                value.len(),
+                match leased {
+                    true => OFFKV_LEASE,
+                    false => 0,
+                }
+            )
+        })?;
+
+        self.session.track(generation, from_error_code(result).map_or(Ok(result), Err))
+    }
+
+    fn erase_once(&self, key: &str, version: i64, deadline: Option<Instant>) -> Result<()> {
+        let (handle, generation) = self.session.handle();
+        let key = to_cstring(key);
+
+        let result = self.session.call(deadline, move || unsafe {
+            offkv_erase(
+                handle.0,
+                key.as_ptr(),
+                version,
+            )
+        })?;
+
+        self.session.track(generation, from_error_code(result as i64).map_or(Ok(()), Err))
+    }
+
+    fn set_once(&self, key: &str, value: &[u8], deadline: Option<Instant>) -> Result<i64> {
+        let (handle, generation) = self.session.handle();
+        let (key, value) = (to_cstring(key), self.encode_value(value)?);
+
+        let result = self.session.call(deadline, move || unsafe {
+            offkv_set(
+                handle.0,
+                key.as_ptr(),
+                value.as_ptr() as *const c_char,
+                value.len(),
+            )
+        })?;
+
+        self.session.track(generation, from_error_code(result).map_or(Ok(result), Err))
+    }
+
+    fn cas_once(&self, key: &str, value: &[u8], version: i64, deadline: Option<Instant>) -> Result<i64> {
+        let (handle, generation) = self.session.handle();
+        let (key, value) = (to_cstring(key), self.encode_value(value)?);
+
+        let result = self.session.call(deadline, move || unsafe {
+            offkv_cas(
+                handle.0,
+                key.as_ptr(),
+                value.as_ptr() as *const c_char,
+                value.len(),
+                version,
+            )
+        })?;
+
+        self.session.track(generation, from_error_code(result).map_or(Ok(result), Err))
+    }
+
+    fn get_once(&self, key: &str, watch: bool, deadline: Option<Instant>)
+           -> Result<(i64, Vec<u8>, Option<WatchHandle<'_>>)> {
+
+        let (handle, generation) = self.session.handle();
+        let (ffi_handle, key) = (Arc::clone(&handle), to_cstring(key));
+
+        let (version, value, watch_handle) = self.session.call(deadline, move || {
+            let mut watch_handle: *mut c_void = match watch {
+                true => ptr::NonNull::dangling().as_ptr(),
+                false => ptr::null_mut(),
+            };
+
+            let offkv_GetResult{version, value, value_size} = unsafe {
+                offkv_get(
+                    ffi_handle.0,
+                    key.as_ptr(),
+                    &mut watch_handle,
+                )
+            };
+
+            if from_error_code(version).is_some() {
+                (version, Vec::new(), None)
+            } else {
+                // <vec_value> now _owns_ the data <value> is pointing at
+                // so on its destroy the data will be freed
+                let vec_value = unsafe {
+                    Vec::from_raw_parts(value as *mut u8, value_size, value_size)
+                };
+
+                (version, vec_value, RawWatch::new(watch_handle))
+            }
+        })?;
+
+        if let Some(error) = from_error_code(version) {
+            self.session.track(generation, Err(error))
+        } else {
+            let watch_handle = watch_handle.map(|watch_handle|
+                WatchHandle::new(self, watch_handle, handle, generation));
+
+            Ok((version, compression::decompress(value)?, watch_handle))
+        }
+    }
+
+    fn exists_once(&self, key: &str, watch: bool, deadline: Option<Instant>)
+        -> Result<(i64, Option<WatchHandle<'_>>)> {
+
+        let (handle, generation) = self.session.handle();
+        let (ffi_handle, key) = (Arc::clone(&handle), to_cstring(key));
+
+        let (result, watch_handle) = self.session.call(deadline, move || {
+            let mut watch_handle: *mut c_void = match watch {
+                true => ptr::NonNull::dangling().as_ptr(),
+                false => ptr::null_mut(),
+            };
+
+            let result = unsafe {
+                offkv_exists(
+                    ffi_handle.0,
+                    key.as_ptr(),
+                    &mut watch_handle,
+                )
+            };
+
+            match from_error_code(result) {
+                Some(_) => (result, None),
+                None => (result, RawWatch::new(watch_handle)),
+            }
+        })?;
+
+        if let Some(error) = from_error_code(result) {
+            self.session.track(generation, Err(error))
+        } else {
+            let watch_handle = watch_handle.map(|watch_handle|
+                WatchHandle::new(self, watch_handle, handle, generation));
+
+            Ok((result, watch_handle))
+        }
+    }
+
+    fn get_children_once(&self, key: &str, watch: bool, deadline: Option<Instant>)
+        -> Result<(Vec<String>, Option<WatchHandle<'_>>)> {
+
+        let (handle, generation) = self.session.handle();
+        let (ffi_handle, key) = (Arc::clone(&handle), to_cstring(key));
+
+        let (error_code, vec, watch_handle) = self.session.call(deadline, move || {
+            let mut watch_handle: *mut c_void = match watch {
+                true => ptr::NonNull::dangling().as_ptr(),
+                false => ptr::null_mut(),
+            };
+
+            let offkv_ChildrenResult{keys, nkeys, error_code} = unsafe {
+                offkv_children(
+                    ffi_handle.0,
+                    key.as_ptr(),
+                    &mut watch_handle,
+                )
+            };
+
+            if from_error_code(error_code as i64).is_some() {
+                return (error_code, Vec::new(), None);
+            }
+
+            let mut vec = Vec::new();
+            unsafe {
+                let keys = slice::from_raw_parts(keys, nkeys);
+                for key in keys {
+                    vec.push(CString::from_raw(*key).into_string().unwrap());
+                }
+            }
+
+            (error_code, vec, RawWatch::new(watch_handle))
+        })?;
+
+        if let Some(error) = from_error_code(error_code as i64) {
+            self.session.track(generation, Err(error))
+        } else {
+            let watch_handle = watch_handle.map(|watch_handle|
+                WatchHandle::new(self, watch_handle, handle, generation));
 
-        // firstly create null-terminated c-strings
-        let cstrings_checks : Vec<CString> =
+            // the counters of sequential keys are an implementation detail
+            let vec = vec.into_iter()
+                .filter(|child| child.rsplit('/').next() != Some(SEQUENCE_COUNTER))
+                .collect();
+
+            Ok((vec, watch_handle))
+        }
+    }
+
+    fn commit_once(&self, transaction: &Transaction, deadline: Option<Instant>)
+        -> Result<Vec<TxnOpResult>> {
+
+        // firstly create owned null-terminated c-strings and values,
+        // the call may outlive the transaction if it doesn't complete in time
+        let owned_checks : Vec<(CString, i64)> =
             transaction.checks
                 .iter()
-                .map(|TxnCheck{key, ..}| to_cstring(key))
+                .map(|TxnCheck{key, version}| (to_cstring(key), *version))
                 .collect();
 
-        let cstrings_ops : Vec<CString> =
+        let owned_ops : Vec<(i32, c_int, CString, Option<Vec<u8>>)> =
             transaction.ops
                 .iter()
-                .map(|op| match *op {
-                    TxnOp::Create{key, ..} |
-                    TxnOp::Set{key, ..} |
-                    TxnOp::Erase{key}
-                        => to_cstring(key)
-                })
-                .collect();
-
-
-        // then pass pointers to them
-        for (TxnCheck{version, ..}, key) in transaction.checks.iter().zip(cstrings_checks.iter()) {
-            checks.push(offkv_TxnCheck{
-                key: key.as_ptr(),
-                version: *version,
-            });
-        }
-
-        let mut ops = Vec::new();
-        for (op, key) in transaction.ops.iter().zip(cstrings_ops.iter()) {
-            match op {
-                TxnOp::Create{value, leased, ..} => {
-                    ops.push(offkv_TxnOp{
-                        op_kind: OffkvTxnOpCode::OFFKV_OP_CREATE as i32,
-                        flags: match *leased {
+                .map(|op| Ok(match op.bytes() {
+                    BytesOp::Create{key, value, leased} => (
+                        OffkvTxnOpCode::OFFKV_OP_CREATE as i32,
+                        match leased {
                             true => OFFKV_LEASE,
                             false => 0
                         },
-                        key: key.as_ptr(),
-                        value: value.as_ptr() as *const c_char,
-                        value_size: value.len(),
-                    });
-                },
-                TxnOp::Set{value, ..} => {
-                    ops.push(offkv_TxnOp{
-                        op_kind: OffkvTxnOpCode::OFFKV_OP_SET as i32,
-                        key: key.as_ptr(),
-                        value: value.as_ptr() as *const c_char,
-                        value_size: value.len(),
-                        // default
-                        flags: 0,
-                    });
-                },
-                TxnOp::Erase{..} => {
-                    ops.push(offkv_TxnOp{
-                        op_kind: OffkvTxnOpCode::OFFKV_OP_ERASE as i32,
-                        key: key.as_ptr(),
-                        // default
-                        flags: 0,
-                        value: ptr::null(),
-                        value_size: 0,
-                    });
-                },
+                        to_cstring(key),
+                        Some(self.encode_value(value)?),
+                    ),
+                    BytesOp::Set{key, value} =>
+                        (OffkvTxnOpCode::OFFKV_OP_SET as i32, 0, to_cstring(key), Some(self.encode_value(value)?)),
+                    BytesOp::Erase{key} =>
+                        (OffkvTxnOpCode::OFFKV_OP_ERASE as i32, 0, to_cstring(key), None),
+                }))
+                .collect::<Result<_>>()?;
+
+        let (handle, generation) = self.session.handle();
+
+        let (error_code, failed_op, results) = self.session.call(deadline, move || {
+            // then pass pointers to them
+            let mut checks = Vec::new();
+            for (key, version) in owned_checks.iter() {
+                checks.push(offkv_TxnCheck{
+                    key: key.as_ptr(),
+                    version: *version,
+                });
             }
-        }
 
-        let mut txn_result = mem::MaybeUninit::uninit();
+            let mut ops = Vec::new();
+            for (op_kind, flags, key, value) in owned_ops.iter() {
+                ops.push(offkv_TxnOp{
+                    op_kind: *op_kind,
+                    flags: *flags,
+                    key: key.as_ptr(),
+                    value: value.as_ref().map_or(ptr::null(), |value| value.as_ptr() as *const c_char),
+                    value_size: value.as_ref().map_or(0, |value| value.len()),
+                });
+            }
 
-        let error_code = unsafe {
-            offkv_commit(
-                self.offkv_handle,
-                checks.as_ptr(),
-                checks.len(),
-                ops.as_ptr(),
-                ops.len(),
-                txn_result.as_mut_ptr(),
-            )
-        };
+            let mut txn_result = mem::MaybeUninit::uninit();
 
-        let offkv_TxnResult{results, nresults, failed_op} =
-            unsafe { txn_result.assume_init() };
+            let error_code = unsafe {
+                offkv_commit(
+                    handle.0,
+                    checks.as_ptr(),
+                    checks.len(),
+                    ops.as_ptr(),
+                    ops.len(),
+                    txn_result.as_mut_ptr(),
+                )
+            };
 
-        match from_error_code(error_code as i64) {
-            Some(OffkvError::TxnFailed(_)) => Err(OffkvError::TxnFailed(failed_op as u32)),
-            Some(error) => Err(error),
-            None => Ok(unsafe { Vec::from_raw_parts(results, nresults, nresults) }
+            let offkv_TxnResult{results, nresults, failed_op} =
+                unsafe { txn_result.assume_init() };
+
+            if from_error_code(error_code as i64).is_some() {
+                return (error_code, failed_op, Vec::new());
+            }
+
+            let results = unsafe { Vec::from_raw_parts(results, nresults, nresults) }
                 .iter()
                 .map(|offkv_TxnOpResult{op_kind, version}|
                     match *op_kind {
@@ -637,15 +1316,15 @@ impl Client {
                             => TxnOpResult::Set(*version),
                         _ => unreachable!(),
                     })
-                .collect())
-        }
-    }
-}
+                .collect();
 
-impl Drop for Client {
-    fn drop(&mut self) {
-        unsafe {
-            offkv_close(self.offkv_handle);
+            (error_code, failed_op, results)
+        })?;
+
+        match from_error_code(error_code as i64) {
+            Some(OffkvError::TxnFailed(_)) => Err(OffkvError::TxnFailed(failed_op as u32)),
+            Some(error) => self.session.track(generation, Err(error)),
+            None => Ok(results),
         }
     }
 }
@@ -0,0 +1,277 @@
+use crate::result::OffkvError;
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// Compression of written values, see `ClientOptions::compression`.
+///
+/// Each algorithm requires the cargo feature of the same name (`zstd`, `lz4`, `gzip`).
+///
+/// Compressed values start with the header `\0okv` followed by a tag of the algorithm,
+/// and plain values starting the same way are escaped on write. Values written otherwise
+/// (by an older rsoffkv version or by other liboffkv clients) that start with `\0okv`
+/// are misread: they fail to decode, or lose their first five bytes if the fifth one is `\0`.
+///
+/// # Example:
+/// ```
+/// use rsoffkv::client::{Client,ClientOptions,Compression};
+/// let client = Client::with_options("consul://localhost:8500", "/test_prefix", ClientOptions{
+///     compression: Some(Compression::Zstd{level: 3}),
+///     ..Default::default()
+/// }).unwrap();
+/// let plain_client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let routes = "10.0.0.1 api\n".repeat(1000);
+/// client.create("/routes", &routes, false).unwrap();
+///
+/// // any client reads it back
+/// assert_eq!(plain_client.get("/routes", false).unwrap().1, routes);
+///
+/// // uncompressed values stay readable
+/// plain_client.set("/routes", "10.0.0.2 api").unwrap();
+/// assert_eq!(client.get("/routes", false).unwrap().1, "10.0.0.2 api");
+///
+/// // even the ones that look like compressed ones
+/// plain_client.set("/routes", "\0okv\x01").unwrap();
+/// assert_eq!(client.get("/routes", false).unwrap().1, "\0okv\x01");
+///
+/// # client.erase("/routes", 0);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard with the given level (1-22, 0 for the default one)
+    #[cfg(feature = "zstd")]
+    Zstd{level: i32},
+
+    /// LZ4, the fastest one
+    #[cfg(feature = "lz4")]
+    Lz4,
+
+    /// gzip with the given level (0-9)
+    #[cfg(feature = "gzip")]
+    Gzip{level: u32},
+}
+
+// compressed values start with the magic followed by the algorithm's tag;
+// plain values starting with the magic are written with the `PLAIN` tag
+const MAGIC: &[u8] = b"\0okv";
+
+const PLAIN: u8 = 0;
+const ZSTD: u8 = 1;
+const LZ4: u8 = 2;
+const GZIP: u8 = 3;
+
+#[cfg(any(feature = "zstd", feature = "gzip"))]
+fn encode_error<E: std::fmt::Display>(error: E) -> OffkvError {
+    OffkvError::Encode(error.to_string())
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4", feature = "gzip"))]
+fn decode_error<E: std::fmt::Display>(error: E) -> OffkvError {
+    OffkvError::Decode(error.to_string())
+}
+
+impl Compression {
+    fn tag(self) -> u8 {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd{..} => ZSTD,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => LZ4,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip{..} => GZIP,
+        }
+    }
+
+    // there's nothing to compress with if no algorithm is enabled
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4", feature = "gzip")), allow(unused_variables, clippy::ptr_arg))]
+    fn compress(self, value: &[u8], compressed: &mut Vec<u8>) -> Result<()> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd{level} =>
+                zstd::stream::copy_encode(value, compressed, level).map_err(encode_error),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                compressed.extend_from_slice(&lz4_flex::compress_prepend_size(value));
+                Ok(())
+            },
+            #[cfg(feature = "gzip")]
+            Compression::Gzip{level} => {
+                use std::io::Write;
+
+                let mut encoder = flate2::write::GzEncoder::new(compressed, flate2::Compression::new(level));
+                encoder.write_all(value).and_then(|_| encoder.finish()).map(|_| ()).map_err(encode_error)
+            },
+        }
+    }
+}
+
+/// Returns the value to write uncompressed, escaping it if it starts with the magic.
+fn plain(value: &[u8]) -> Vec<u8> {
+    if !value.starts_with(MAGIC) {
+        return value.to_vec();
+    }
+
+    let mut escaped = MAGIC.to_vec();
+    escaped.push(PLAIN);
+    escaped.extend_from_slice(value);
+    escaped
+}
+
+/// Compresses the value if it's at least `min_size` bytes long and gets smaller.
+///
+/// # Returns:
+///
+/// * the value to write or `OffkvError::Encode` if the compression fails
+pub(crate) fn compress(compression: Option<Compression>, min_size: usize, value: &[u8]) -> Result<Vec<u8>> {
+    let compression = match compression {
+        Some(compression) if value.len() >= min_size => compression,
+        _ => return Ok(plain(value)),
+    };
+
+    let mut compressed = MAGIC.to_vec();
+    compressed.push(compression.tag());
+    compression.compress(value, &mut compressed)?;
+
+    Ok(match compressed.len() < value.len() {
+        true => compressed,
+        false => plain(value),
+    })
+}
+
+/// Decompresses an LZ4 block prepended with its size, checking the size first.
+#[cfg(feature = "lz4")]
+fn decompress_lz4(compressed: &[u8]) -> Result<Vec<u8>> {
+    if compressed.len() < 4 {
+        return Err(OffkvError::Decode(String::from("the lz4 size is truncated")));
+    }
+
+    // a block can't expand more than 255 times, a bigger size is corrupt
+    let size = u32::from_le_bytes([compressed[0], compressed[1], compressed[2], compressed[3]]) as usize;
+    if size > (compressed.len() - 4).saturating_mul(255) {
+        return Err(OffkvError::Decode(format!("the lz4 size {} doesn't match the block", size)));
+    }
+    lz4_flex::decompress(&compressed[4..], size).map_err(decode_error)
+}
+
+/// Decompresses the value if it has the header, returns other values as is.
+///
+/// # Returns:
+///
+/// * the value or `OffkvError::Decode` if it has a malformed header or fails to decompress
+#[cfg_attr(not(any(feature = "zstd", feature = "lz4", feature = "gzip")), allow(unused_variables))]
+pub(crate) fn decompress(mut value: Vec<u8>) -> Result<Vec<u8>> {
+    if !value.starts_with(MAGIC) {
+        return Ok(value);
+    }
+    if value.len() == MAGIC.len() {
+        return Err(OffkvError::Decode(String::from("the compression header is truncated")));
+    }
+
+    let compressed = &value[MAGIC.len() + 1..];
+    match value[MAGIC.len()] {
+        PLAIN => {
+            value.drain(..MAGIC.len() + 1);
+            Ok(value)
+        },
+        #[cfg(feature = "zstd")]
+        ZSTD => zstd::stream::decode_all(compressed).map_err(decode_error),
+        #[cfg(feature = "lz4")]
+        LZ4 => decompress_lz4(compressed),
+        #[cfg(feature = "gzip")]
+        GZIP => {
+            use std::io::Read;
+
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(compressed).read_to_end(&mut decompressed).map_err(decode_error)?;
+            Ok(decompressed)
+        },
+        #[cfg(not(feature = "zstd"))]
+        ZSTD => Err(OffkvError::Decode(String::from("the value is compressed with zstd, enable the `zstd` feature"))),
+        #[cfg(not(feature = "lz4"))]
+        LZ4 => Err(OffkvError::Decode(String::from("the value is compressed with lz4, enable the `lz4` feature"))),
+        #[cfg(not(feature = "gzip"))]
+        GZIP => Err(OffkvError::Decode(String::from("the value is compressed with gzip, enable the `gzip` feature"))),
+        tag => Err(OffkvError::Decode(format!("unknown compression {}", tag))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(compression: Option<Compression>, value: &[u8]) -> Vec<u8> {
+        let written = compress(compression, 16, value).unwrap();
+        assert_eq!(decompress(written.clone()).unwrap(), value);
+        written
+    }
+
+    #[test]
+    fn short_and_incompressible_values_are_written_plain() {
+        assert_eq!(round_trip(None, b"value"), b"value");
+        assert_eq!(round_trip(None, &[7; 100]), vec![7; 100]);
+    }
+
+    #[test]
+    fn values_looking_compressed_are_escaped() {
+        for value in [&b"\0okv"[..], b"\0okv\x01garbage", b"\0okv\0"].iter() {
+            let written = round_trip(None, value);
+            assert_eq!(&written[..MAGIC.len() + 1], b"\0okv\0");
+        }
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        assert!(decompress(MAGIC.to_vec()).is_err());
+        assert!(decompress(b"\0okv\xffdata".to_vec()).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trips() {
+        let value = "10.0.0.1 api\n".repeat(100);
+        assert!(round_trip(Some(Compression::Zstd{level: 3}), value.as_bytes()).len() < value.len());
+        round_trip(Some(Compression::Zstd{level: 0}), b"\0okv and then some more bytes");
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trips() {
+        let value = "10.0.0.1 api\n".repeat(100);
+        assert!(round_trip(Some(Compression::Lz4), value.as_bytes()).len() < value.len());
+        round_trip(Some(Compression::Lz4), b"\0okv and then some more bytes");
+        round_trip(Some(Compression::Lz4), &vec![0; 1 << 20]);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_sizes_beyond_the_block_are_rejected() {
+        let mut written = compress(Some(Compression::Lz4), 0, "value ".repeat(100).as_bytes()).unwrap();
+        written[MAGIC.len() + 1..MAGIC.len() + 5].copy_from_slice(&u32::MAX.to_le_bytes());
+        match decompress(written) {
+            Err(OffkvError::Decode(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(decompress(b"\0okv\x02\x01".to_vec()).is_err());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_round_trips() {
+        let value = "10.0.0.1 api\n".repeat(100);
+        assert!(round_trip(Some(Compression::Gzip{level: 6}), value.as_bytes()).len() < value.len());
+        round_trip(Some(Compression::Gzip{level: 1}), b"\0okv and then some more bytes");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn corrupt_compressed_values_fail_to_decode() {
+        let mut written = compress(Some(Compression::Zstd{level: 3}), 0, "value ".repeat(100).as_bytes()).unwrap();
+        written.truncate(written.len() / 2);
+        match decompress(written) {
+            Err(OffkvError::Decode(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
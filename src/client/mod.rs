@@ -1,4 +1,11 @@
 mod ffi;
+#[allow(clippy::module_inception)]
 mod client;
+mod session;
+mod compression;
+mod cached;
 
-pub use client::Client;
+pub use cached::{CacheOptions,CacheStats,CachedClient};
+pub use client::{Client,WatchEvent,WatchHandle};
+pub use compression::Compression;
+pub use session::{ClientOptions,SessionEvent,SessionState};
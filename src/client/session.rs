@@ -0,0 +1,575 @@
+use std::collections::BTreeMap;
+use std::os::raw::{c_char,c_void};
+use std::ptr;
+use std::sync::{Arc,Mutex,RwLock,Weak,mpsc};
+use std::sync::atomic::{AtomicIsize,AtomicU64,AtomicUsize,Ordering};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use super::compression::{self,Compression};
+use super::ffi::*;
+
+use crate::backoff::Backoff;
+use crate::result::*;
+use crate::retry::RetryPolicy;
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// Session lifecycle events, see `Client::session_events`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// the client is connected to the service
+    Connected,
+
+    /// the connection was lost; operations fail with `ConnectionLost` until
+    /// the client reconnects
+    Suspended,
+
+    /// the old session is gone together with its leased keys and watches
+    Expired,
+
+    /// a new session was established after `Expired`
+    Reconnected,
+}
+
+/// Current state of the client's session, see `Client::session_state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// the session is usable
+    Connected,
+
+    /// the connection was lost and the client is (possibly) reconnecting
+    Suspended,
+}
+
+/// Client settings, see `Client::with_options`.
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    /// if set, the client reopens the session after a connection loss, sleeping
+    /// according to the backoff between attempts; otherwise (the default)
+    /// a suspended client stays suspended
+    pub reconnect: Option<Backoff>,
+
+    /// if set, the connection is probed at this interval, so a lost connection
+    /// suspends the session even while the client is idle; not set by default
+    pub heartbeat: Option<Duration>,
+
+    /// if `true`, leased keys created by the client are created again
+    /// (with the last written value) once a new session is established.
+    /// A key still held by the old session is taken over only if it has
+    /// the version the client last wrote, otherwise the key is considered lost
+    pub recreate_leased: bool,
+
+    /// if set, operations failed with transient errors are retried.
+    ///
+    /// `get`, `exists` and `get_children` are simply repeated, `set` is repeated
+    /// on `ConnectionLost` only. Other operations are not idempotent, and the failed attempt
+    /// may have been applied, so if a retry fails because of the state the failed attempt
+    /// could have caused, the outcome is resolved by re-reading the keys: `create`, `cas`
+    /// and `commit` succeed if the written keys hold the written values (and the keys
+    /// a `commit` erases are gone), and `erase` missing the key succeeds. Otherwise
+    /// the client returns `OffkvError::OutcomeUnknown`. Note that a concurrent writer
+    /// of the same value is taken for the failed attempt.
+    pub retry: Option<RetryPolicy>,
+
+    /// time limit for each operation, see `Client::with_deadline`
+    pub default_deadline: Option<Duration>,
+
+    /// how optimistic helpers (e.g. `Client::update`) start over after losing a race
+    /// to a concurrent writer
+    pub conflicts: RetryPolicy,
+
+    /// if set, values written with `create`, `set`, `cas` and `commit` are compressed.
+    /// Compressed values carry a header naming the algorithm, and reads decompress them
+    /// whatever the client's own setting is, so uncompressed values stay readable.
+    /// Uncompressed values starting like the header are escaped by every client
+    pub compression: Option<Compression>,
+
+    /// values shorter than this many bytes are never compressed
+    pub compression_min_size: usize,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions{
+            reconnect: None,
+            heartbeat: None,
+            recreate_leased: false,
+            retry: None,
+            default_deadline: None,
+            conflicts: RetryPolicy{
+                backoff: Backoff{
+                    initial: Duration::from_millis(10),
+                    max: Duration::from_secs(1),
+                    multiplier: 2.,
+                    jitter: 0.5,
+                },
+                max_attempts: 20,
+                deadline: None,
+            },
+            compression: None,
+            compression_min_size: 256,
+        }
+    }
+}
+
+
+/// Owned liboffkv client handle, closed on drop.
+pub(crate) struct Handle(pub(crate) *mut c_void);
+
+// liboffkv clients may be used from any thread
+unsafe impl Send for Handle {}
+unsafe impl Sync for Handle {}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            offkv_close(self.0);
+        }
+    }
+}
+
+impl Handle {
+    fn open(url: &str, prefix: &str) -> Result<Self> {
+        let mut error_code: i32 = 0;
+
+        let offkv_handle: *mut c_void = unsafe {
+            offkv_open(
+                to_cstring(url).as_ptr(),
+                to_cstring(prefix).as_ptr(),
+                &mut error_code,
+            )
+        };
+
+        from_error_code(error_code as i64).map_or(Ok(Handle(offkv_handle)), Err)
+    }
+}
+
+
+// probed by the heartbeat, doesn't need to exist
+const HEARTBEAT_KEY: &str = "/.rsoffkv-heartbeat";
+
+// calls with a deadline are run by at most this many threads per session
+const MAX_CALLING_THREADS: usize = 16;
+
+// a calling thread exits after waiting this long for a call
+const CALLING_THREAD_IDLE: Duration = Duration::from_secs(30);
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+
+/// Threads running the calls made with a deadline (see `Session::call`), so that
+/// the caller can give up waiting for a stuck call.
+///
+/// Threads are spawned on demand, up to `MAX_CALLING_THREADS`, and exit once idle
+/// for `CALLING_THREAD_IDLE` or once the session is gone. A call left running after
+/// its caller gave up keeps its thread busy until it returns.
+struct Callers {
+    sender: mpsc::Sender<Call>,
+    receiver: Arc<Mutex<mpsc::Receiver<Call>>>,
+    threads: Arc<AtomicUsize>,
+    // idle threads minus queued calls
+    available: Arc<AtomicIsize>,
+}
+
+// a call returns the callback delivering its result, run once the thread is available again
+type Call = Box<dyn FnOnce() -> Callback + Send>;
+
+impl Callers {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Callers{
+            sender,
+            receiver: Arc::new(Mutex::new(receiver)),
+            threads: Arc::new(AtomicUsize::new(0)),
+            available: Arc::new(AtomicIsize::new(0)),
+        }
+    }
+
+    fn run(&self, call: Call) {
+        let _ = self.sender.send(call);
+
+        // an idle thread takes the call, including one that is about to exit,
+        // as it checks for calls once more
+        if self.available.fetch_sub(1, Ordering::SeqCst) > 0 {
+            return;
+        }
+        let spawned = self.threads.fetch_update(Ordering::SeqCst, Ordering::SeqCst,
+            |threads| if threads < MAX_CALLING_THREADS { Some(threads + 1) } else { None });
+        if spawned.is_err() {
+            // the call waits for a busy thread
+            return;
+        }
+
+        self.available.fetch_add(1, Ordering::SeqCst);
+        let (receiver, threads, available) =
+            (Arc::clone(&self.receiver), Arc::clone(&self.threads), Arc::clone(&self.available));
+        thread::Builder::new()
+            .name(String::from("rsoffkv-call"))
+            .spawn(move || Callers::serve(&receiver, &threads, &available))
+            .expect("Failed to spawn calling thread");
+    }
+
+    fn serve(receiver: &Mutex<mpsc::Receiver<Call>>, threads: &AtomicUsize, available: &AtomicIsize) {
+        loop {
+            let call = {
+                let receiver = receiver.lock().unwrap();
+                match receiver.recv_timeout(CALLING_THREAD_IDLE) {
+                    Ok(call) => call,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        available.fetch_sub(1, Ordering::SeqCst);
+                        // a call sent after the decrement spawns another thread
+                        match receiver.try_recv() {
+                            Ok(call) => {
+                                available.fetch_add(1, Ordering::SeqCst);
+                                call
+                            },
+                            Err(_) => {
+                                threads.fetch_sub(1, Ordering::SeqCst);
+                                return;
+                            },
+                        }
+                    },
+                    // the session is gone
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            };
+
+            let deliver = call();
+            available.fetch_add(1, Ordering::SeqCst);
+            deliver();
+        }
+    }
+}
+
+
+/// Connection state shared by a client and its heartbeat and reconnecting threads.
+///
+/// Every reconnection replaces the handle and bumps the generation, so
+/// callers can tell whether something they obtained earlier (e.g. a watch)
+/// belongs to the current session.
+pub(crate) struct Session {
+    url: String,
+    prefix: String,
+    options: ClientOptions,
+    handle: RwLock<Arc<Handle>>,
+    generation: AtomicU64,
+    state: Mutex<SessionState>,
+    listeners: Mutex<Vec<mpsc::Sender<SessionEvent>>>,
+    // callbacks run once the current handle is replaced
+    replaced: Mutex<BTreeMap<u64, Callback>>,
+    next_callback: AtomicU64,
+    // leased keys with their last written values and versions
+    leased: Mutex<BTreeMap<String, (Vec<u8>, i64)>>,
+    callers: Callers,
+}
+
+impl Session {
+    pub(crate) fn open(url: &str, prefix: &str, options: ClientOptions) -> Result<Arc<Self>> {
+        let handle = Handle::open(url, prefix)?;
+
+        let session = Arc::new(Session{
+            url: String::from(url),
+            prefix: String::from(prefix),
+            options,
+            handle: RwLock::new(Arc::new(handle)),
+            generation: AtomicU64::new(0),
+            state: Mutex::new(SessionState::Connected),
+            listeners: Mutex::new(Vec::new()),
+            replaced: Mutex::new(BTreeMap::new()),
+            next_callback: AtomicU64::new(0),
+            leased: Mutex::new(BTreeMap::new()),
+            callers: Callers::new(),
+        });
+
+        if let Some(interval) = session.options.heartbeat {
+            let weak = Arc::downgrade(&session);
+            thread::Builder::new()
+                .name(String::from("rsoffkv-heartbeat"))
+                .spawn(move || Session::heartbeat(weak, interval))
+                .expect("Failed to spawn heartbeat thread");
+        }
+        Ok(session)
+    }
+
+    /// Returns the current handle and its generation.
+    pub(crate) fn handle(&self) -> (Arc<Handle>, u64) {
+        let handle = self.handle.read().unwrap();
+        (Arc::clone(&handle), self.generation.load(Ordering::SeqCst))
+    }
+
+    pub(crate) fn options(&self) -> &ClientOptions {
+        &self.options
+    }
+
+    /// Runs the blocking call, giving up at the deadline (if any) with `OffkvError::Timeout`.
+    ///
+    /// A call with a deadline is run by one of the session's calling threads.
+    /// A call that isn't completed in time is left running, its results are dropped
+    /// once it returns; a call that hasn't started by the deadline is skipped.
+    pub(crate) fn call<T, F>(&self, deadline: Option<Instant>, call: F) -> Result<T>
+        where T: Send + 'static, F: FnOnce() -> T + Send + 'static {
+
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return Ok(call()),
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(OffkvError::Timeout);
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        self.callers.run(Box::new(move || {
+            let result = (Instant::now() < deadline).then(call);
+            Box::new(move || if let Some(result) = result {
+                let _ = sender.send(result);
+            })
+        }));
+
+        receiver.recv_timeout(remaining).map_err(|_| OffkvError::Timeout)
+    }
+
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn state(&self) -> SessionState {
+        *self.state.lock().unwrap()
+    }
+
+    pub(crate) fn subscribe(&self) -> mpsc::Receiver<SessionEvent> {
+        let (sender, receiver) = mpsc::channel();
+
+        if self.state() == SessionState::Connected {
+            let _ = sender.send(SessionEvent::Connected);
+        }
+        self.listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    /// Inspects the result of an operation performed on the handle of the
+    /// given generation and suspends the session if the connection was lost.
+    pub(crate) fn track<T>(self: &Arc<Self>, generation: u64, result: Result<T>) -> Result<T> {
+        if let Err(OffkvError::ConnectionLost) = result {
+            self.suspend(generation);
+        }
+        result
+    }
+
+    /// Runs the callback once the handle of the given generation is replaced,
+    /// right away if it already has been.
+    ///
+    /// Returns the id to cancel the callback with, `None` if it has already run.
+    pub(crate) fn on_replaced(&self, generation: u64, callback: Callback) -> Option<u64> {
+        let mut replaced = self.replaced.lock().unwrap();
+        if generation != self.generation() {
+            drop(replaced);
+            callback();
+            return None;
+        }
+
+        let id = self.next_callback.fetch_add(1, Ordering::Relaxed);
+        replaced.insert(id, callback);
+        Some(id)
+    }
+
+    /// Drops the callback, returns `false` if it has already run.
+    pub(crate) fn cancel_replaced(&self, id: u64) -> bool {
+        self.replaced.lock().unwrap().remove(&id).is_some()
+    }
+
+    pub(crate) fn remember_leased(&self, key: &str, value: &[u8], version: i64) {
+        self.leased.lock().unwrap().insert(String::from(key), (value.to_vec(), version));
+    }
+
+    /// Updates the value to recreate the key with if the key is a leased one.
+    pub(crate) fn refresh_leased(&self, key: &str, value: &[u8], version: i64) {
+        if let Some(stored) = self.leased.lock().unwrap().get_mut(key) {
+            *stored = (value.to_vec(), version);
+        }
+    }
+
+    /// Forgets the key and all its descendants.
+    pub(crate) fn forget_leased(&self, key: &str) {
+        let subtree = format!("{}/", key);
+        self.leased.lock().unwrap().retain(|leased, _| leased != key && !leased.starts_with(&subtree));
+    }
+
+    fn emit(&self, event: SessionEvent) {
+        self.listeners.lock().unwrap().retain(|listener| listener.send(event).is_ok());
+    }
+
+    fn suspend(self: &Arc<Self>, generation: u64) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if *state == SessionState::Suspended || generation != self.generation() {
+                return;
+            }
+            *state = SessionState::Suspended;
+        }
+        self.emit(SessionEvent::Suspended);
+
+        if let Some(backoff) = self.options.reconnect.clone() {
+            let session = Arc::downgrade(self);
+            thread::Builder::new()
+                .name(String::from("rsoffkv-reconnect"))
+                .spawn(move || Session::reconnect(session, backoff))
+                .expect("Failed to spawn reconnecting thread");
+        }
+    }
+
+    fn heartbeat(session: Weak<Session>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+
+            let session = match session.upgrade() {
+                Some(session) => session,
+                None => return,
+            };
+            if session.state() == SessionState::Suspended {
+                continue;
+            }
+
+            let (handle, generation) = session.handle();
+            let mut no_watch: *mut c_void = ptr::null_mut();
+            let result = unsafe {
+                offkv_exists(handle.0, to_cstring(HEARTBEAT_KEY).as_ptr(), &mut no_watch)
+            };
+            let _ = session.track(generation, from_error_code(result).map_or(Ok(()), Err));
+        }
+    }
+
+    fn reconnect(session: Weak<Session>, backoff: Backoff) {
+        for attempt in 0.. {
+            thread::sleep(backoff.delay(attempt));
+
+            // the client is gone, nobody needs the session anymore
+            let session = match session.upgrade() {
+                Some(session) => session,
+                None => return,
+            };
+
+            if let Ok(handle) = Handle::open(&session.url, &session.prefix) {
+                session.install(handle);
+                return;
+            }
+        }
+    }
+
+    fn install(&self, handle: Handle) {
+        {
+            // the old handle is closed as soon as the last watch using it is dropped
+            let mut current = self.handle.write().unwrap();
+            *current = Arc::new(handle);
+            self.generation.fetch_add(1, Ordering::SeqCst);
+        }
+        let replaced = std::mem::take(&mut *self.replaced.lock().unwrap());
+        for callback in replaced.into_values() {
+            callback();
+        }
+        self.emit(SessionEvent::Expired);
+
+        if self.options.recreate_leased {
+            self.recreate_leased();
+        }
+
+        *self.state.lock().unwrap() = SessionState::Connected;
+        self.emit(SessionEvent::Reconnected);
+    }
+
+    fn recreate_leased(&self) {
+        let (handle, _) = self.handle();
+        let leased = self.leased.lock().unwrap().clone();
+
+        for (key, (value, version)) in leased.into_iter() {
+            let options = self.options();
+            let encoded = match compression::compress(options.compression, options.compression_min_size, &value) {
+                Ok(encoded) => encoded,
+                // tried again after the next reconnection
+                Err(_) => continue,
+            };
+            let c_key = to_cstring(&key);
+            let create = || unsafe {
+                offkv_create(
+                    handle.0,
+                    c_key.as_ptr(),
+                    encoded.as_ptr() as *const c_char,
+                    encoded.len(),
+                    OFFKV_LEASE,
+                )
+            };
+
+            let mut result = create();
+            // the key may still be held by the old session, take it over
+            // unless someone else has written it since (then the erase does nothing)
+            if let (Some(OffkvError::EntryExists), true) = (from_error_code(result), version > 0) {
+                unsafe {
+                    offkv_erase(handle.0, c_key.as_ptr(), version);
+                }
+                result = create();
+            }
+
+            match from_error_code(result) {
+                None => self.refresh_leased(&key, &value, result),
+                // held by someone else, or the parent is gone
+                Some(OffkvError::EntryExists) | Some(OffkvError::NoEntry) => self.forget_leased(&key),
+                // tried again after the next reconnection
+                Some(_) => {},
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Condvar;
+
+    #[test]
+    fn callers_reuse_idle_threads() {
+        let callers = Callers::new();
+        let mut threads = Vec::new();
+        for _ in 0..20 {
+            let (sender, receiver) = mpsc::channel();
+            callers.run(Box::new(move || {
+                let id = thread::current().id();
+                Box::new(move || { let _ = sender.send(id); })
+            }));
+            threads.push(receiver.recv().unwrap());
+        }
+
+        threads.dedup();
+        assert_eq!(threads.len(), 1);
+        assert_eq!(callers.threads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn callers_queue_calls_once_all_threads_are_busy() {
+        let callers = Callers::new();
+        let released = Arc::new((Mutex::new(false), Condvar::new()));
+        let (sender, receiver) = mpsc::channel();
+
+        for n in 0..MAX_CALLING_THREADS + 4 {
+            let (released, sender) = (Arc::clone(&released), sender.clone());
+            callers.run(Box::new(move || {
+                let (lock, condvar) = &*released;
+                let _released = condvar.wait_while(lock.lock().unwrap(), |released| !*released).unwrap();
+                Box::new(move || { let _ = sender.send(n); })
+            }));
+        }
+        assert_eq!(callers.threads.load(Ordering::SeqCst), MAX_CALLING_THREADS);
+
+        *released.0.lock().unwrap() = true;
+        released.1.notify_all();
+
+        let mut done: Vec<_> = receiver.iter().take(MAX_CALLING_THREADS + 4).collect();
+        done.sort();
+        assert_eq!(done, (0..MAX_CALLING_THREADS + 4).collect::<Vec<_>>());
+    }
+}
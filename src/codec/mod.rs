@@ -0,0 +1,280 @@
+//! Typed values (via serde), requires one of the codec features:
+//! `json`, `cbor`, `msgpack` or `bincode`.
+//!
+//! `TypedClient` encodes and decodes the values of its operations with a `Codec`.
+//! Codecs can also be used directly, e.g. to build `Transaction` ops or in
+//! `Client::transact`, see `Json`. Values that can't be decoded are reported as
+//! `OffkvError::Decode`.
+
+use std::marker::PhantomData;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::client::{Client,WatchHandle};
+use crate::result::OffkvError;
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// Encoding of values.
+pub trait Codec {
+    /// Encodes the value, returns `OffkvError::Encode` on failure.
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>>;
+
+    /// Decodes a value, returns `OffkvError::Decode` on failure.
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+fn encode_error<E: std::fmt::Display>(error: E) -> OffkvError {
+    OffkvError::Encode(error.to_string())
+}
+
+fn decode_error<E: std::fmt::Display>(error: E) -> OffkvError {
+    OffkvError::Decode(error.to_string())
+}
+
+/// JSON codec, requires the `json` feature.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::codec::{Json,TypedClient};
+/// use rsoffkv::result::OffkvError;
+/// use rsoffkv::txn::{Transaction,TxnCheck,TxnOp};
+/// use serde::{Deserialize,Serialize};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// #[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// struct Route {
+///     upstream: String,
+///     weight: u32,
+/// }
+///
+/// let routes = TypedClient::<Json>::new(&client);
+/// let version = routes.create("/route", &Route{upstream: String::from("api-1"), weight: 10}, false).unwrap();
+///
+/// let (_, route, _) = routes.get::<Route>("/route", false).unwrap();
+/// assert_eq!(route, Route{upstream: String::from("api-1"), weight: 10});
+///
+/// // values stay readable as text
+/// assert_eq!(client.get("/route", false).unwrap().1, r#"{"upstream":"api-1","weight":10}"#);
+///
+/// // in a transaction (or with `Json::encode`)
+/// let value = routes.encode(&Route{upstream: String::from("api-2"), weight: 5}).unwrap();
+/// client.commit(Transaction{
+///     checks: vec![TxnCheck{key: "/route", version}],
+///     ops: vec![TxnOp::SetBytes{key: "/route", value: &value}],
+/// }).unwrap();
+///
+/// routes.update("/route", |route: Option<Route>| route.map(|route| Route{weight: route.weight * 2, ..route})).unwrap();
+/// assert_eq!(routes.get::<Route>("/route", false).unwrap().1.weight, 10);
+///
+/// // a value of another type
+/// client.set("/route", "not a route").unwrap();
+/// match routes.get::<Route>("/route", false) {
+///     Err(OffkvError::Decode(_)) => {},
+///     _ => panic!("the value must fail to decode"),
+/// }
+///
+/// # client.erase("/route", 0);
+/// ```
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(encode_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        serde_json::from_slice(bytes).map_err(decode_error)
+    }
+}
+
+/// CBOR codec, requires the `cbor` feature.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::codec::{Cbor,TypedClient};
+/// use std::collections::BTreeMap;
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let limits = TypedClient::<Cbor>::new(&client);
+/// let mut quotas = BTreeMap::new();
+/// quotas.insert(String::from("uploads"), 100u64);
+/// limits.set("/quotas", &quotas).unwrap();
+///
+/// assert_eq!(limits.get::<BTreeMap<String, u64>>("/quotas", false).unwrap().1, quotas);
+///
+/// # client.erase("/quotas", 0);
+/// ```
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        let mut encoded = Vec::new();
+        ciborium::ser::into_writer(value, &mut encoded).map_err(encode_error)?;
+        Ok(encoded)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        ciborium::de::from_reader(bytes).map_err(decode_error)
+    }
+}
+
+/// MessagePack codec, requires the `msgpack` feature. Structs are encoded as maps,
+/// so fields can be added without breaking older readers.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::codec::{MessagePack,TypedClient};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let hosts = TypedClient::<MessagePack>::new(&client);
+/// hosts.set("/hosts", &vec!["10.0.0.1", "10.0.0.2"]).unwrap();
+///
+/// assert_eq!(hosts.get::<Vec<String>>("/hosts", false).unwrap().1, vec!["10.0.0.1", "10.0.0.2"]);
+///
+/// # client.erase("/hosts", 0);
+/// ```
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        rmp_serde::to_vec_named(value).map_err(encode_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        rmp_serde::from_slice(bytes).map_err(decode_error)
+    }
+}
+
+/// Bincode codec, requires the `bincode` feature. The most compact one, but
+/// the encoding isn't self-describing: readers must use exactly the same type.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::codec::{Bincode,TypedClient};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let offsets = TypedClient::<Bincode>::new(&client);
+/// offsets.set("/offsets", &(42u64, -7i32)).unwrap();
+///
+/// assert_eq!(offsets.get::<(u64, i32)>("/offsets", false).unwrap().1, (42, -7));
+///
+/// # client.erase("/offsets", 0);
+/// ```
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+        bincode::serialize(value).map_err(encode_error)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        bincode::deserialize(bytes).map_err(decode_error)
+    }
+}
+
+/// Client encoding and decoding values with the codec, see `Json` for an example.
+///
+/// The methods match the ones of `Client` with the same names.
+pub struct TypedClient<C: Codec> {
+    client: Client,
+    codec: PhantomData<C>,
+}
+
+impl<C: Codec> Clone for TypedClient<C> {
+    fn clone(&self) -> Self {
+        TypedClient::new(&self.client)
+    }
+}
+
+impl<C: Codec> TypedClient<C> {
+    /// Wraps the client; clones share the session.
+    pub fn new(client: &Client) -> Self {
+        TypedClient{client: client.clone(), codec: PhantomData}
+    }
+
+    /// Returns the underlying client, e.g. for the operations on keys
+    /// that don't involve values.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Encodes the value with the codec, e.g. for the ops of a `Transaction`.
+    ///
+    /// # Returns:
+    ///
+    /// * the encoded value or `OffkvError::Encode`
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        C::encode(value)
+    }
+
+    /// Same as `Client::create` with the encoded value.
+    ///
+    /// # Returns:
+    ///
+    /// * initial version
+    /// * `OffkvError::Encode` if the value can't be encoded, nothing is created then
+    pub fn create<T: Serialize + ?Sized>(&self, key: &str, value: &T, leased: bool) -> Result<i64> {
+        self.client.create(key, C::encode(value)?, leased)
+    }
+
+    /// Same as `Client::set` with the encoded value.
+    ///
+    /// # Returns:
+    ///
+    /// * new version of the key
+    /// * `OffkvError::Encode` if the value can't be encoded, the key is left intact then
+    pub fn set<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<i64> {
+        self.client.set(key, C::encode(value)?)
+    }
+
+    /// Same as `Client::cas` with the encoded value.
+    ///
+    /// # Returns:
+    ///
+    /// * new version of the key or 0 on failure (given version not equal to current)
+    /// * `OffkvError::Encode` if the value can't be encoded, the key is left intact then
+    pub fn cas<T: Serialize + ?Sized>(&self, key: &str, value: &T, version: i64) -> Result<i64> {
+        self.client.cas(key, C::encode(value)?, version)
+    }
+
+    /// # Returns:
+    ///
+    /// * current version of the key
+    /// * current value
+    /// * (optional) `WatchHandle`
+    /// * `OffkvError::Decode` if the value can't be decoded into `T`
+    pub fn get<T: DeserializeOwned>(&self, key: &str, watch: bool) -> Result<(i64, T, Option<WatchHandle<'_>>)> {
+        let (version, value, watch_handle) = self.client.get_bytes(key, watch)?;
+        Ok((version, C::decode(&value)?, watch_handle))
+    }
+
+    /// Same as `Client::update`. A current value that can't be decoded stops
+    /// the update with `OffkvError::Decode`, leaving the value intact.
+    pub fn update<T, F>(&self, key: &str, mut f: F) -> Result<i64>
+        where T: Serialize + DeserializeOwned, F: FnMut(Option<T>) -> Option<T> {
+
+        self.client.try_update(key, |old| {
+            let old = match old {
+                Some(old) => Some(C::decode(old)?),
+                None => None,
+            };
+            f(old).map(|new| C::encode(&new)).transpose()
+        })
+    }
+}
@@ -0,0 +1,332 @@
+//! Typed configuration read from a subtree of the store, requires the `config` feature.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc,Mutex,Weak,mpsc};
+use std::thread;
+
+use arc_swap::ArcSwap;
+use serde::de::DeserializeOwned;
+use serde_json::{Map,Value};
+
+use crate::backoff::Backoff;
+use crate::client::Client;
+use crate::result::OffkvError;
+use crate::txn::TxnContext;
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// Change of the configuration, see `Watcher::subscribe`.
+pub struct ConfigChange<T> {
+    pub old: Arc<T>,
+    pub new: Arc<T>,
+}
+
+/// Configuration loaded from a subtree and kept up to date.
+///
+/// The subtree is turned into a JSON object: a key having children becomes an object
+/// of its children named by the last path segment, a value of a leaf key is parsed
+/// as JSON and taken as a string if it's not valid JSON. The object is then
+/// deserialized into `T`.
+///
+/// The subtree is read in a transaction checking the versions of all keys read,
+/// so a snapshot never mixes values from before and after a concurrent update of
+/// several keys. Each key is watched (both its value and its children), and a snapshot
+/// is only taken if the watches set after the read saw the same values and children,
+/// so a key added during the read isn't missed. A background thread reloads the subtree
+/// on any change; it's stopped on drop (liboffkv can't cancel a watch, so the watches
+/// pending then are dropped once their keys change). States that can't be deserialized
+/// are skipped, keeping the last valid snapshot.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::config::Watcher;
+/// use serde::Deserialize;
+/// use std::time::Duration;
+///
+/// #[derive(Deserialize, Debug, PartialEq)]
+/// struct Database {
+///     host: String,
+///     port: u16,
+/// }
+///
+/// #[derive(Deserialize, Debug, PartialEq)]
+/// struct Config {
+///     db: Database,
+///     debug: bool,
+/// }
+///
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// client.create("/config", "", false).unwrap();
+/// client.create("/config/debug", "false", false).unwrap();
+/// client.create("/config/db", "", false).unwrap();
+/// client.create("/config/db/host", "localhost", false).unwrap();
+/// client.create("/config/db/port", "5432", false).unwrap();
+///
+/// let config = Watcher::<Config>::new(&client, "/config").unwrap();
+/// assert_eq!(config.current().db.host, "localhost");
+///
+/// let changes = config.subscribe();
+/// client.set("/config/debug", "true").unwrap();
+///
+/// let change = changes.recv_timeout(Duration::from_secs(5)).unwrap();
+/// assert!(!change.old.debug);
+/// assert!(change.new.debug);
+/// assert!(config.current().debug);
+///
+/// # drop(config);
+/// # client.erase("/config", 0);
+/// ```
+pub struct Watcher<T> {
+    shared: Arc<Shared<T>>,
+    signals: mpsc::Sender<Signal>,
+}
+
+struct Shared<T> {
+    current: ArcSwap<T>,
+    listeners: Mutex<Vec<mpsc::Sender<ConfigChange<T>>>>,
+}
+
+enum Signal {
+    // the value of the key changed
+    Value(String),
+    // the children of the key changed
+    Children(String),
+    Stop,
+}
+
+/// Key of the subtree as read by a snapshot.
+struct Node {
+    key: String,
+    value: Vec<u8>,
+    children: Vec<String>,
+}
+
+/// State of the thread reloading the configuration.
+struct Follower<T> {
+    client: Client,
+    root: String,
+    shared: Weak<Shared<T>>,
+    // the watches report here
+    signals: mpsc::Sender<Signal>,
+    // the last loaded subtree
+    tree: Value,
+    // keys watched, flagged whether the children are watched
+    watched: BTreeSet<(String, bool)>,
+}
+
+impl<T: DeserializeOwned + Send + Sync + 'static> Watcher<T> {
+    /// Loads the configuration and starts following its changes.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the configuration is read with
+    /// * `root` - key of the subtree
+    ///
+    /// # Returns:
+    ///
+    /// * the watcher or `OffkvError::Decode` if the subtree doesn't match `T`
+    pub fn new(client: &Client, root: &str) -> Result<Self> {
+        let (signals, received) = mpsc::channel();
+        let mut follower = Follower{
+            client: client.clone(),
+            root: String::from(root),
+            shared: Weak::new(),
+            signals: signals.clone(),
+            tree: Value::Null,
+            watched: BTreeSet::new(),
+        };
+
+        follower.tree = follower.snapshot()?;
+        let current = serde_json::from_value(follower.tree.clone())
+            .map_err(|error| OffkvError::Decode(error.to_string()))?;
+
+        let shared = Arc::new(Shared{
+            current: ArcSwap::from_pointee(current),
+            listeners: Mutex::new(Vec::new()),
+        });
+        follower.shared = Arc::downgrade(&shared);
+
+        thread::Builder::new()
+            .name(String::from("rsoffkv-config"))
+            .spawn(move || follower.follow(received))
+            .expect("Failed to spawn config watcher");
+
+        Ok(Watcher{shared, signals})
+    }
+
+    /// Returns the current snapshot.
+    pub fn current(&self) -> Arc<T> {
+        self.shared.current.load_full()
+    }
+
+    /// Subscribes to changes of the configuration.
+    pub fn subscribe(&self) -> mpsc::Receiver<ConfigChange<T>> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+impl<T> Drop for Watcher<T> {
+    fn drop(&mut self) {
+        let _ = self.signals.send(Signal::Stop);
+    }
+}
+
+/// Reads the subtree consistently.
+///
+/// # Returns:
+///
+/// * all the keys of the subtree and the subtree as JSON
+fn read(client: &Client, root: &str) -> Result<(Vec<Node>, Value)> {
+    client.transact(|txn| {
+        let mut nodes = Vec::new();
+        let tree = read_node(client, txn, root, &mut nodes)?;
+        Ok((nodes, tree))
+    })
+}
+
+fn read_node(client: &Client, txn: &mut TxnContext, key: &str, nodes: &mut Vec<Node>) -> Result<Value> {
+    let value = match txn.get(key)? {
+        Some(value) => value,
+        None => return Ok(Value::Null),
+    };
+
+    let children = match client.get_children(key, false) {
+        Ok((children, _)) => children,
+        Err(OffkvError::NoEntry) => Vec::new(),
+        Err(error) => return Err(error),
+    };
+    nodes.push(Node{key: String::from(key), value: value.clone(), children: children.clone()});
+
+    if children.is_empty() {
+        return Ok(serde_json::from_slice(&value)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&value).into_owned())));
+    }
+
+    let mut object = Map::new();
+    for child in children {
+        let name = child[child.rfind('/').map_or(0, |position| position + 1)..].to_string();
+        let value = read_node(client, txn, &child, nodes)?;
+        if !value.is_null() {
+            object.insert(name, value);
+        }
+    }
+    Ok(Value::Object(object))
+}
+
+impl<T: DeserializeOwned + Send + Sync + 'static> Follower<T> {
+    /// Reloads the configuration as the watches report changes until the watcher is dropped.
+    fn follow(mut self, signals: mpsc::Receiver<Signal>) {
+        let mut failures = 0;
+        let mut signal = Err(mpsc::RecvTimeoutError::Timeout);
+
+        loop {
+            // the changes reported meanwhile are picked up by a single reload
+            loop {
+                match signal {
+                    Ok(Signal::Value(key)) => { self.watched.remove(&(key, false)); },
+                    Ok(Signal::Children(key)) => { self.watched.remove(&(key, true)); },
+                    Ok(Signal::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {},
+                }
+                signal = match signals.try_recv() {
+                    Ok(signal) => Ok(signal),
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => Err(mpsc::RecvTimeoutError::Disconnected),
+                };
+            }
+
+            let shared = match self.shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+
+            signal = match self.reload(&shared) {
+                // a state that can't be deserialized is skipped until the next change
+                Ok(()) | Err(OffkvError::Decode(_)) => {
+                    failures = 0;
+                    drop(shared);
+                    signals.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+                },
+                Err(_) => {
+                    failures += 1;
+                    drop(shared);
+                    signals.recv_timeout(Backoff::default().delay(failures - 1))
+                },
+            };
+        }
+    }
+
+    /// Reads the subtree again, replacing the snapshot if it changed.
+    fn reload(&mut self, shared: &Shared<T>) -> Result<()> {
+        let tree = self.snapshot()?;
+        if tree == self.tree {
+            return Ok(());
+        }
+
+        let new: Arc<T> = Arc::new(serde_json::from_value(tree.clone())
+            .map_err(|error| OffkvError::Decode(error.to_string()))?);
+        let old = shared.current.swap(Arc::clone(&new));
+        self.tree = tree;
+
+        shared.listeners.lock().unwrap().retain(|listener| listener.send(ConfigChange{
+            old: Arc::clone(&old),
+            new: Arc::clone(&new),
+        }).is_ok());
+        Ok(())
+    }
+
+    /// Reads the subtree until the watches of all the keys read saw the same state.
+    fn snapshot(&mut self) -> Result<Value> {
+        loop {
+            let (nodes, tree) = read(&self.client, &self.root)?;
+            if self.arm(&nodes)? {
+                return Ok(tree);
+            }
+        }
+    }
+
+    /// Watches the keys not watched yet.
+    ///
+    /// Returns whether the watches saw the values and the children that were read.
+    fn arm(&mut self, nodes: &[Node]) -> Result<bool> {
+        let mut seen = true;
+
+        for node in nodes {
+            if !self.watched.contains(&(node.key.clone(), false)) {
+                match self.client.get_bytes(&node.key, true) {
+                    Ok((_, value, watch_handle)) => {
+                        if let Some(watch_handle) = watch_handle {
+                            self.watched.insert((node.key.clone(), false));
+                            let changed = || Signal::Value(node.key.clone());
+                            watch_handle.notify(self.signals.clone(), changed(), changed());
+                        }
+                        seen &= value == node.value;
+                    },
+                    Err(OffkvError::NoEntry) => seen = false,
+                    Err(error) => return Err(error),
+                }
+            }
+
+            if !self.watched.contains(&(node.key.clone(), true)) {
+                match self.client.get_children(&node.key, true) {
+                    Ok((children, watch_handle)) => {
+                        if let Some(watch_handle) = watch_handle {
+                            self.watched.insert((node.key.clone(), true));
+                            let changed = || Signal::Children(node.key.clone());
+                            watch_handle.notify(self.signals.clone(), changed(), changed());
+                        }
+                        seen &= children == node.children;
+                    },
+                    Err(OffkvError::NoEntry) => seen = false,
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+        Ok(seen)
+    }
+}
@@ -0,0 +1,503 @@
+//! Client-side envelope encryption of values, requires the `encryption` feature.
+//!
+//! Each value is sealed with its own random data key, and the data key is sealed
+//! with a master key of the `Keyring`. The sealed value carries the id of the master
+//! key, so after a rotation (see `Keyring::rotate`) values sealed with older keys
+//! stay readable while they are re-encrypted (see `EncryptedClient::reencrypt`).
+//! The store key is authenticated together with the value, so a sealed value can't be
+//! moved to another key.
+//!
+//! Layout of a sealed value: the magic `\0oke`, the cipher, the length of the master
+//! key id and the id, the nonce and the sealed data key, the nonce and the sealed value.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead,AeadCore,KeyInit,OsRng,Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::client::{Client,WatchHandle};
+use crate::recipes;
+use crate::result::OffkvError;
+use crate::txn::{Transaction,TxnCheck,TxnOp};
+
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack", feature = "bincode"))]
+use crate::codec::Codec;
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack", feature = "bincode"))]
+use serde::{Serialize,de::DeserializeOwned};
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// AEAD cipher values are sealed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+const MAGIC: &[u8] = b"\0oke";
+const NONCE_SIZE: usize = 12;
+const KEY_SIZE: usize = 32;
+const TAG_SIZE: usize = 16;
+
+impl Cipher {
+    fn tag(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Cipher::Aes256Gcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Returns the nonce followed by the ciphertext.
+    fn seal(self, key: &[u8; KEY_SIZE], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let payload = Payload{msg: plaintext, aad};
+        let (nonce, sealed) = match self {
+            Cipher::Aes256Gcm => {
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                (nonce.to_vec(), Aes256Gcm::new(key.into()).encrypt(&nonce, payload))
+            },
+            Cipher::ChaCha20Poly1305 => {
+                let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+                (nonce.to_vec(), ChaCha20Poly1305::new(key.into()).encrypt(&nonce, payload))
+            },
+        };
+
+        // fails only if the plaintext exceeds the cipher's limit of gigabytes
+        let mut sealed_with_nonce = nonce;
+        sealed_with_nonce.extend(sealed.expect("Failed to seal the value"));
+        sealed_with_nonce
+    }
+
+    fn open(self, key: &[u8; KEY_SIZE], nonce: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload{msg: sealed, aad};
+        match self {
+            Cipher::Aes256Gcm => Aes256Gcm::new(key.into()).decrypt(aes_gcm::Nonce::from_slice(nonce), payload),
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into()).decrypt(chacha20poly1305::Nonce::from_slice(nonce), payload),
+        }.map_err(|_| OffkvError::Decode(String::from("the value failed authentication")))
+    }
+}
+
+/// Master keys values are sealed with.
+///
+/// New values are sealed with the primary key; the other keys are used only to open
+/// values sealed before a rotation.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: BTreeMap<String, [u8; KEY_SIZE]>,
+    primary: String,
+    cipher: Cipher,
+}
+
+/// Parsed header of a sealed value.
+struct Sealed<'a> {
+    cipher: Cipher,
+    key_id: &'a str,
+    data_key: &'a [u8],
+    value: &'a [u8],
+}
+
+/// Key ids are stored with a one-byte length.
+fn check_id(id: &str) -> Result<()> {
+    match id.len() <= u8::MAX as usize {
+        true => Ok(()),
+        false => Err(OffkvError::InvalidArgument(format!("the key id is {} bytes long, at most 255 are allowed", id.len()))),
+    }
+}
+
+fn parse(sealed: &[u8]) -> Option<Sealed<'_>> {
+    let rest = sealed.strip_prefix(MAGIC)?;
+    let (cipher, id_size) = (Cipher::from_tag(*rest.first()?)?, *rest.get(1)? as usize);
+    let rest = &rest[2..];
+
+    let data_key_size = NONCE_SIZE + KEY_SIZE + TAG_SIZE;
+    if rest.len() < id_size + data_key_size + NONCE_SIZE + TAG_SIZE {
+        return None;
+    }
+
+    let (key_id, rest) = rest.split_at(id_size);
+    let (data_key, value) = rest.split_at(data_key_size);
+    Some(Sealed{cipher, key_id: std::str::from_utf8(key_id).ok()?, data_key, value})
+}
+
+impl Keyring {
+    /// Creates a keyring sealing values with AES-256-GCM.
+    ///
+    /// # Arguments:
+    ///
+    /// * `id` - id of the primary key, at most 255 bytes long; it's stored in every value
+    /// * `key` - the primary key, e.g. fetched from a KMS; must never leave the application
+    ///
+    /// # Returns:
+    ///
+    /// * the keyring or `OffkvError::InvalidArgument` if the id is too long
+    pub fn new(id: &str, key: [u8; KEY_SIZE]) -> Result<Self> {
+        Keyring::with_cipher(id, key, Cipher::Aes256Gcm)
+    }
+
+    /// Creates a keyring sealing values with the given cipher. Values sealed with
+    /// any of the ciphers can be opened. Fails like `new`.
+    pub fn with_cipher(id: &str, key: [u8; KEY_SIZE], cipher: Cipher) -> Result<Self> {
+        check_id(id)?;
+
+        let mut keys = BTreeMap::new();
+        keys.insert(String::from(id), key);
+        Ok(Keyring{keys, primary: String::from(id), cipher})
+    }
+
+    /// Adds a key to open values sealed with it, e.g. a former primary key.
+    ///
+    /// # Returns:
+    ///
+    /// * `OffkvError::InvalidArgument` if the id is longer than 255 bytes
+    pub fn add(&mut self, id: &str, key: [u8; KEY_SIZE]) -> Result<()> {
+        check_id(id)?;
+        self.keys.insert(String::from(id), key);
+        Ok(())
+    }
+
+    /// Adds a key and makes it the primary one. The former primary key is kept
+    /// to open the values sealed with it. Fails like `add`.
+    pub fn rotate(&mut self, id: &str, key: [u8; KEY_SIZE]) -> Result<()> {
+        self.add(id, key)?;
+        self.primary = String::from(id);
+        Ok(())
+    }
+
+    /// Returns the id of the primary key.
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    /// Returns the id of the key the value is sealed with, `None` if the value is not sealed.
+    pub fn key_id(sealed: &[u8]) -> Option<&str> {
+        parse(sealed).map(|sealed| sealed.key_id)
+    }
+
+    /// Seals the value to be stored under the key with the primary key.
+    ///
+    /// # Arguments:
+    ///
+    /// * `key` - the store key the value is written to; the value can only be opened for this key
+    /// * `value` - the plaintext
+    pub fn seal(&self, key: &str, value: &[u8]) -> Vec<u8> {
+        let data_key = Aes256Gcm::generate_key(&mut OsRng);
+        let data_key: &[u8; KEY_SIZE] = data_key.as_ref();
+
+        let mut sealed = MAGIC.to_vec();
+        sealed.push(self.cipher.tag());
+        sealed.push(self.primary.len() as u8);
+        sealed.extend_from_slice(self.primary.as_bytes());
+        sealed.extend(self.cipher.seal(&self.keys[&self.primary], data_key, self.primary.as_bytes()));
+        sealed.extend(self.cipher.seal(data_key, value, key.as_bytes()));
+        sealed
+    }
+
+    /// Opens the value read from the key.
+    ///
+    /// # Returns:
+    ///
+    /// * the plaintext
+    /// * `OffkvError::Decode` if the value is not sealed, it's sealed with an unknown key,
+    ///   or it fails authentication (it has been tampered with or belongs to another key)
+    pub fn open(&self, key: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+        let sealed = parse(sealed).ok_or_else(|| OffkvError::Decode(String::from("the value is not sealed")))?;
+        let master_key = self.keys.get(sealed.key_id)
+            .ok_or_else(|| OffkvError::Decode(format!("unknown key id {}", sealed.key_id)))?;
+
+        let (nonce, data_key) = sealed.data_key.split_at(NONCE_SIZE);
+        let data_key = sealed.cipher.open(master_key, nonce, data_key, sealed.key_id.as_bytes())?;
+        let data_key: [u8; KEY_SIZE] = data_key[..].try_into()
+            .map_err(|_| OffkvError::Decode(String::from("malformed data key")))?;
+
+        let (nonce, value) = sealed.value.split_at(NONCE_SIZE);
+        sealed.cipher.open(&data_key, nonce, value, key.as_bytes())
+    }
+}
+
+/// Client sealing the values it writes and opening the values it reads with a keyring.
+///
+/// For transactions, seal the values with `Keyring::seal` (see the example) and open
+/// the values read in `Client::transact` with `Keyring::open`. With one of the codec
+/// features, `get_as` and `set_as` combine a codec with encryption.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::encryption::{EncryptedClient,Keyring};
+/// use rsoffkv::result::OffkvError;
+/// use rsoffkv::txn::{Transaction,TxnOp};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let mut keyring = Keyring::new("2024-01", [7; 32]).unwrap();
+/// let secrets = EncryptedClient::new(&client, keyring.clone());
+///
+/// client.create("/secrets", "", false).unwrap();
+/// secrets.set("/secrets/db", "hunter2").unwrap();
+/// assert_eq!(secrets.get("/secrets/db", false).unwrap().1, b"hunter2");
+///
+/// // the store only sees the sealed value
+/// let (_, stored, _) = client.get_bytes("/secrets/db", false).unwrap();
+/// assert_eq!(Keyring::key_id(&stored), Some("2024-01"));
+///
+/// // sealed values can be written in transactions
+/// let token = keyring.seal("/secrets/api", b"s3cr3t");
+/// client.commit(Transaction{
+///     checks: vec![],
+///     ops: vec![TxnOp::CreateBytes{key: "/secrets/api", value: &token, leased: false}],
+/// }).unwrap();
+///
+/// // a sealed value can't be moved to another key
+/// client.set("/secrets/db", &token).unwrap();
+/// match secrets.get("/secrets/db", false) {
+///     Err(OffkvError::Decode(_)) => {},
+///     _ => panic!("the value must fail authentication"),
+/// }
+/// secrets.set("/secrets/db", "hunter2").unwrap();
+///
+/// // rotation: new values are sealed with the new key, the old ones are re-encrypted
+/// keyring.rotate("2024-02", [8; 32]).unwrap();
+/// let secrets = EncryptedClient::new(&client, keyring);
+/// assert_eq!(secrets.reencrypt("/secrets", 1).unwrap(), 2);
+///
+/// let (_, stored, _) = client.get_bytes("/secrets/api", false).unwrap();
+/// assert_eq!(Keyring::key_id(&stored), Some("2024-02"));
+/// assert_eq!(secrets.get("/secrets/api", false).unwrap().1, b"s3cr3t");
+///
+/// # client.erase("/secrets", 0);
+/// ```
+#[derive(Clone)]
+pub struct EncryptedClient {
+    client: Client,
+    keyring: Keyring,
+}
+
+impl EncryptedClient {
+    /// Creates a client sealing and opening the values with the keyring.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the sealed values are stored with
+    /// * `keyring` - the master keys
+    pub fn new(client: &Client, keyring: Keyring) -> Self {
+        EncryptedClient{client: client.clone(), keyring}
+    }
+
+    /// Returns the underlying client, e.g. for the operations on keys
+    /// that don't involve values.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns the keyring, e.g. to seal values written in transactions.
+    pub fn keyring(&self) -> &Keyring {
+        &self.keyring
+    }
+
+    /// Same as `Client::create`, sealing the value.
+    pub fn create<V: AsRef<[u8]>>(&self, key: &str, value: V, leased: bool) -> Result<i64> {
+        self.client.create(key, self.keyring.seal(key, value.as_ref()), leased)
+    }
+
+    /// Same as `Client::set`, sealing the value.
+    pub fn set<V: AsRef<[u8]>>(&self, key: &str, value: V) -> Result<i64> {
+        self.client.set(key, self.keyring.seal(key, value.as_ref()))
+    }
+
+    /// Same as `Client::cas`, sealing the value.
+    pub fn cas<V: AsRef<[u8]>>(&self, key: &str, value: V, version: i64) -> Result<i64> {
+        self.client.cas(key, self.keyring.seal(key, value.as_ref()), version)
+    }
+
+    /// Same as `Client::get_bytes`, opening the value.
+    ///
+    /// # Returns:
+    ///
+    /// * current version of the key
+    /// * the opened value
+    /// * (optional) `WatchHandle`
+    /// * `OffkvError::Decode` if the value can't be opened, see `Keyring::open`
+    pub fn get(&self, key: &str, watch: bool) -> Result<(i64, Vec<u8>, Option<WatchHandle<'_>>)> {
+        let (version, sealed, watch_handle) = self.client.get_bytes(key, watch)?;
+        Ok((version, self.keyring.open(key, &sealed)?, watch_handle))
+    }
+
+    /// Same as `Client::update`; a value that can't be opened stops the update
+    /// with `OffkvError::Decode`, leaving the value intact.
+    pub fn update<F>(&self, key: &str, mut f: F) -> Result<i64>
+        where F: FnMut(Option<&[u8]>) -> Option<Vec<u8>> {
+
+        self.client.try_update(key, |old| {
+            let old = match old {
+                Some(old) => Some(self.keyring.open(key, old)?),
+                None => None,
+            };
+            Ok(f(old.as_deref()).map(|new| self.keyring.seal(key, &new)))
+        })
+    }
+
+    /// Re-seals the values in the subtree (the key included) sealed with other keys
+    /// than the primary one. Each batch of values is written in one `commit` checking
+    /// that the values haven't changed, starting the batch over otherwise
+    /// (see `ClientOptions::conflicts`). Values that aren't sealed are left intact.
+    ///
+    /// # Arguments:
+    ///
+    /// * `root` - root of the subtree
+    /// * `batch_size` - number of values per `commit`
+    ///
+    /// # Returns:
+    ///
+    /// * number of values re-sealed
+    /// * `OffkvError::Decode` if a value is sealed with a key missing in the keyring
+    pub fn reencrypt(&self, root: &str, batch_size: usize) -> Result<usize> {
+        let mut reencrypted = 0;
+        for batch in recipes::subtree(&self.client, root)?.chunks(batch_size.max(1)) {
+            reencrypted += self.client.options().conflicts.run_optimistic(|_| self.reencrypt_batch(batch))?;
+        }
+        Ok(reencrypted)
+    }
+
+    /// Returns the number of values re-sealed, `None` on conflict.
+    fn reencrypt_batch(&self, keys: &[String]) -> Result<Option<usize>> {
+        let mut resealed = Vec::new();
+        for key in keys {
+            let (version, sealed) = match self.client.get_bytes(key, false) {
+                Ok((version, sealed, _)) => (version, sealed),
+                Err(OffkvError::NoEntry) => continue,
+                Err(error) => return Err(error),
+            };
+
+            match Keyring::key_id(&sealed) {
+                Some(key_id) if key_id != self.keyring.primary() => {
+                    let value = self.keyring.open(key, &sealed)?;
+                    resealed.push((key, version, self.keyring.seal(key, &value)));
+                },
+                _ => {},
+            }
+        }
+
+        if resealed.is_empty() {
+            return Ok(Some(0));
+        }
+
+        match self.client.commit(Transaction{
+            checks: resealed.iter().map(|(key, version, _)| TxnCheck{key, version: *version}).collect(),
+            ops: resealed.iter().map(|(key, _, value)| TxnOp::SetBytes{key, value}).collect(),
+        }) {
+            Ok(_) => Ok(Some(resealed.len())),
+            Err(OffkvError::TxnFailed(_)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack", feature = "bincode"))]
+impl EncryptedClient {
+    /// Encodes the value with the codec and sets the key to it sealed.
+    pub fn set_as<C: Codec, T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<i64> {
+        self.set(key, C::encode(value)?)
+    }
+
+    /// Opens the value of the key and decodes it with the codec.
+    pub fn get_as<C: Codec, T: DeserializeOwned>(&self, key: &str, watch: bool) -> Result<(i64, T, Option<WatchHandle<'_>>)> {
+        let (version, value, watch_handle) = self.get(key, watch)?;
+        Ok((version, C::decode(&value)?, watch_handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_decode_error(result: Result<Vec<u8>>) {
+        match result {
+            Err(OffkvError::Decode(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sealed_values_open_with_both_ciphers() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::ChaCha20Poly1305].iter() {
+            let keyring = Keyring::with_cipher("k1", [1; KEY_SIZE], *cipher).unwrap();
+            let sealed = keyring.seal("/secret", b"hunter2");
+
+            assert_eq!(Keyring::key_id(&sealed), Some("k1"));
+            assert_eq!(keyring.open("/secret", &sealed).unwrap(), b"hunter2");
+        }
+    }
+
+    #[test]
+    fn sealing_is_randomized() {
+        let keyring = Keyring::new("k1", [1; KEY_SIZE]).unwrap();
+        assert_ne!(keyring.seal("/secret", b"hunter2"), keyring.seal("/secret", b"hunter2"));
+        assert_eq!(keyring.open("/secret", &keyring.seal("/secret", b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn values_are_bound_to_their_key() {
+        let keyring = Keyring::new("k1", [1; KEY_SIZE]).unwrap();
+        let sealed = keyring.seal("/secret", b"hunter2");
+        assert_decode_error(keyring.open("/another", &sealed));
+    }
+
+    #[test]
+    fn tampered_and_plain_values_are_rejected() {
+        let keyring = Keyring::new("k1", [1; KEY_SIZE]).unwrap();
+        let mut sealed = keyring.seal("/secret", b"hunter2");
+        *sealed.last_mut().unwrap() ^= 1;
+
+        assert_decode_error(keyring.open("/secret", &sealed));
+        assert_decode_error(keyring.open("/secret", b"hunter2"));
+        assert_decode_error(keyring.open("/secret", &sealed[..MAGIC.len() + 4]));
+        assert_eq!(Keyring::key_id(b"hunter2"), None);
+    }
+
+    #[test]
+    fn rotation_keeps_older_values_readable() {
+        let mut keyring = Keyring::new("k1", [1; KEY_SIZE]).unwrap();
+        let old = keyring.seal("/secret", b"old");
+
+        keyring.rotate("k2", [2; KEY_SIZE]).unwrap();
+        assert_eq!(keyring.primary(), "k2");
+        let new = keyring.seal("/secret", b"new");
+
+        assert_eq!(Keyring::key_id(&old), Some("k1"));
+        assert_eq!(Keyring::key_id(&new), Some("k2"));
+        assert_eq!(keyring.open("/secret", &old).unwrap(), b"old");
+        assert_eq!(keyring.open("/secret", &new).unwrap(), b"new");
+
+        // a keyring without the former key can't open its values
+        let rotated = Keyring::new("k2", [2; KEY_SIZE]).unwrap();
+        assert_decode_error(rotated.open("/secret", &old));
+        assert_eq!(rotated.open("/secret", &new).unwrap(), b"new");
+    }
+
+    #[test]
+    fn a_key_with_a_known_id_but_other_bytes_fails_authentication() {
+        let sealed = Keyring::new("k1", [1; KEY_SIZE]).unwrap().seal("/secret", b"hunter2");
+        assert_decode_error(Keyring::new("k1", [9; KEY_SIZE]).unwrap().open("/secret", &sealed));
+    }
+
+    #[test]
+    fn long_key_ids_are_rejected() {
+        let long = "k".repeat(256);
+        assert!(matches!(Keyring::new(&long, [1; KEY_SIZE]), Err(OffkvError::InvalidArgument(_))));
+
+        let mut keyring = Keyring::new(&long[..255], [1; KEY_SIZE]).unwrap();
+        assert!(matches!(keyring.add(&long, [2; KEY_SIZE]), Err(OffkvError::InvalidArgument(_))));
+        assert!(matches!(keyring.rotate(&long, [2; KEY_SIZE]), Err(OffkvError::InvalidArgument(_))));
+        assert_eq!(keyring.primary(), &long[..255]);
+    }
+}
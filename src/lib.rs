@@ -1,5 +1,19 @@
 extern crate libc;
 
+pub mod backoff;
 pub mod result;
+pub mod retry;
 pub mod txn;
 pub mod client;
+pub mod recipes;
+pub mod chunked;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "scheduler")]
+pub mod scheduler;
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack", feature = "bincode"))]
+pub mod codec;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack", feature = "bincode"))]
+pub mod migrations;
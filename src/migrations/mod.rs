@@ -0,0 +1,401 @@
+//! Schema versions of typed values, requires one of the codec features (see `rsoffkv::codec`).
+//!
+//! Values written through a `VersionedClient` are tagged with the current version
+//! of their `Schema`: the magic `\0oks` followed by the version (4 bytes, big-endian)
+//! and the encoded value. Values without the tag are taken as version 0, so records
+//! written before versioning was introduced are upgraded as well.
+//!
+//! A value of an older version is upgraded on read by applying the registered upgrades
+//! one version at a time. Upgraded values can be written back right away
+//! (see `VersionedClient::new`) or all at once with `VersionedClient::migrate`.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::client::{Client,WatchHandle};
+use crate::codec::Codec;
+use crate::recipes;
+use crate::result::OffkvError;
+use crate::txn::{Transaction,TxnCheck,TxnOp};
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+const MAGIC: &[u8] = b"\0oks";
+
+type Upgrade = Box<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+/// Outcome of `VersionedClient::migrate`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// values upgraded and written back
+    pub migrated: usize,
+
+    /// keys whose values can't be decoded or upgraded (see `Schema::decode`), left as they are
+    pub skipped: Vec<String>,
+}
+
+/// Versions of a type stored with the codec `C`, `T` being the current one.
+pub struct Schema<C: Codec, T> {
+    version: u32,
+    upgrades: BTreeMap<u32, Upgrade>,
+    types: PhantomData<fn() -> (C, T)>,
+}
+
+/// Splits a stored value into its version and the encoded value.
+fn untag(stored: &[u8]) -> (u32, &[u8]) {
+    match stored.strip_prefix(MAGIC) {
+        Some(tagged) if tagged.len() >= 4 => {
+            let (version, encoded) = tagged.split_at(4);
+            (u32::from_be_bytes(version.try_into().unwrap()), encoded)
+        },
+        _ => (0, stored),
+    }
+}
+
+impl<C: Codec, T: Serialize + DeserializeOwned> Schema<C, T> {
+    /// Creates a schema of the given current version. Upgrades from all the older
+    /// versions still stored must be registered.
+    pub fn new(version: u32) -> Self {
+        Schema{version, upgrades: BTreeMap::new(), types: PhantomData}
+    }
+
+    /// Returns the current version.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Registers the upgrade of values of the version to the next one.
+    ///
+    /// # Arguments:
+    ///
+    /// * `from` - version the upgrade applies to (less than the current one)
+    /// * `upgrade` - converts the value of the version `from`, `Old`,
+    ///   into the value of the version `from + 1`, `New`
+    ///
+    /// # Returns:
+    ///
+    /// * `OffkvError::InvalidArgument` if `from` is not older than the current version
+    pub fn register<Old, New, F>(&mut self, from: u32, upgrade: F) -> Result<()>
+        where Old: DeserializeOwned, New: Serialize, F: Fn(Old) -> New + Send + Sync + 'static {
+
+        if from >= self.version {
+            return Err(OffkvError::InvalidArgument(
+                format!("upgrade from version {} is not older than the current one", from)));
+        }
+        self.upgrades.insert(from, Box::new(move |encoded| C::encode(&upgrade(C::decode::<Old>(encoded)?))));
+        Ok(())
+    }
+
+    /// Encodes the value and tags it with the current version.
+    pub fn encode(&self, value: &T) -> Result<Vec<u8>> {
+        let mut stored = MAGIC.to_vec();
+        stored.extend_from_slice(&self.version.to_be_bytes());
+        stored.extend(C::encode(value)?);
+        Ok(stored)
+    }
+
+    /// Decodes the stored value upgrading it to the current version.
+    ///
+    /// # Returns:
+    ///
+    /// * the value and the version it was stored with
+    /// * `OffkvError::Decode` if the value is of a newer version, an upgrade is missing
+    ///   or the value can't be decoded
+    pub fn decode(&self, stored: &[u8]) -> Result<(T, u32)> {
+        let (stored_version, encoded) = untag(stored);
+        if stored_version > self.version {
+            return Err(OffkvError::Decode(format!("the value is of newer version {}", stored_version)));
+        }
+
+        let mut encoded = encoded.to_vec();
+        for version in stored_version..self.version {
+            let upgrade = self.upgrades.get(&version)
+                .ok_or_else(|| OffkvError::Decode(format!("no upgrade from version {}", version)))?;
+            encoded = upgrade(&encoded)?;
+        }
+        Ok((C::decode(&encoded)?, stored_version))
+    }
+}
+
+/// Client reading and writing values of a `Schema`.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::codec::Json;
+/// use rsoffkv::migrations::{MigrationReport,Schema,VersionedClient};
+/// use serde::{Deserialize,Serialize};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// // version 0, written before versioning
+/// #[derive(Serialize, Deserialize)]
+/// struct UserV0 {
+///     name: String,
+/// }
+///
+/// // version 1
+/// #[derive(Serialize, Deserialize)]
+/// struct UserV1 {
+///     first_name: String,
+///     last_name: String,
+/// }
+///
+/// // version 2, the current one
+/// #[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// struct User {
+///     first_name: String,
+///     last_name: String,
+///     admin: bool,
+/// }
+///
+/// client.create("/users", "", false).unwrap();
+/// client.create("/users/ada", r#"{"name":"Ada Lovelace"}"#, false).unwrap();
+/// client.create("/users/alan", r#"{"name":"Alan Turing"}"#, false).unwrap();
+/// client.create("/users/bob", "not a user", false).unwrap();
+///
+/// let mut schema = Schema::<Json, User>::new(2);
+/// schema.register(0, |user: UserV0| {
+///     let mut names = user.name.splitn(2, ' ');
+///     UserV1{
+///         first_name: names.next().unwrap_or("").to_string(),
+///         last_name: names.next().unwrap_or("").to_string(),
+///     }
+/// }).unwrap();
+/// schema.register(1, |user: UserV1| User{first_name: user.first_name, last_name: user.last_name, admin: false}).unwrap();
+///
+/// let users = VersionedClient::new(&client, schema, false);
+///
+/// // upgraded on read
+/// let (_, ada, _) = users.get("/users/ada", false).unwrap();
+/// assert_eq!(ada, User{first_name: "Ada".to_string(), last_name: "Lovelace".to_string(), admin: false});
+///
+/// // rewrites the old records, skipping keys with empty values and reporting broken ones
+/// let report = users.migrate("/users", 10).unwrap();
+/// assert_eq!(report, MigrationReport{migrated: 2, skipped: vec!["/users/bob".to_string()]});
+/// assert_eq!(users.migrate("/users", 10).unwrap().migrated, 0);
+///
+/// # client.erase("/users", 0);
+/// ```
+pub struct VersionedClient<C: Codec, T> {
+    client: Client,
+    schema: Arc<Schema<C, T>>,
+    write_back: bool,
+}
+
+impl<C: Codec, T> Clone for VersionedClient<C, T> {
+    fn clone(&self) -> Self {
+        VersionedClient{client: self.client.clone(), schema: Arc::clone(&self.schema), write_back: self.write_back}
+    }
+}
+
+impl<C: Codec, T: Serialize + DeserializeOwned> VersionedClient<C, T> {
+    /// # Arguments:
+    ///
+    /// * `client` - client the values are read and written with
+    /// * `schema` - versions of the values
+    /// * `write_back` - if `true`, values upgraded on read are written back with `cas`
+    pub fn new(client: &Client, schema: Schema<C, T>, write_back: bool) -> Self {
+        VersionedClient{client: client.clone(), schema: Arc::new(schema), write_back}
+    }
+
+    /// Returns the underlying client, e.g. for the operations on keys
+    /// that don't involve values.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Returns the schema of the values.
+    pub fn schema(&self) -> &Schema<C, T> {
+        &self.schema
+    }
+
+    /// Encodes the value with the current version of the schema and creates the key,
+    /// see `Client::create`.
+    ///
+    /// # Returns:
+    ///
+    /// * initial version of the key
+    /// * `OffkvError::Encode` if the value can't be encoded
+    pub fn create(&self, key: &str, value: &T, leased: bool) -> Result<i64> {
+        self.client.create(key, self.schema.encode(value)?, leased)
+    }
+
+    /// Encodes the value with the current version of the schema and assigns it,
+    /// see `Client::set`.
+    ///
+    /// # Returns:
+    ///
+    /// * new version of the key
+    /// * `OffkvError::Encode` if the value can't be encoded
+    pub fn set(&self, key: &str, value: &T) -> Result<i64> {
+        self.client.set(key, self.schema.encode(value)?)
+    }
+
+    /// Encodes the value with the current version of the schema and assigns it
+    /// if the key's version matches, see `Client::cas`.
+    ///
+    /// # Returns:
+    ///
+    /// * new version of the key, 0 if the version didn't match
+    /// * `OffkvError::Encode` if the value can't be encoded
+    pub fn cas(&self, key: &str, value: &T, version: i64) -> Result<i64> {
+        self.client.cas(key, self.schema.encode(value)?, version)
+    }
+
+    /// Returns the current version and the value of the key, upgraded to the current
+    /// version of the schema.
+    ///
+    /// If the value has been upgraded and written back, the returned version is
+    /// the one written, and the returned `WatchHandle` (set before the write) is
+    /// woken up by the write itself. A failed write-back is ignored, the value is
+    /// upgraded again on the next read.
+    ///
+    /// # Returns:
+    ///
+    /// * current version of the key
+    /// * the value
+    /// * (optional) `WatchHandle`
+    /// * `OffkvError::Decode` if the value can't be decoded, see `Schema::decode`
+    pub fn get(&self, key: &str, watch: bool) -> Result<(i64, T, Option<WatchHandle<'_>>)> {
+        let (mut version, stored, watch_handle) = self.client.get_bytes(key, watch)?;
+        let (value, stored_version) = self.schema.decode(&stored)?;
+
+        if self.write_back && stored_version < self.schema.version() {
+            // losing to a concurrent writer (0) is fine, and so is failing to write
+            let written = self.schema.encode(&value).and_then(|encoded| self.client.cas(key, encoded, version));
+            if let Ok(written) = written {
+                if written != 0 {
+                    version = written;
+                }
+            }
+        }
+        Ok((version, value, watch_handle))
+    }
+
+    /// Upgrades the values in the subtree (the key included) stored with older versions
+    /// of the schema. Each batch of values is written in one `commit` checking that
+    /// the values haven't changed, starting the batch over otherwise
+    /// (see `ClientOptions::conflicts`). Keys with empty values (e.g. the parents
+    /// of the records) are skipped, and so are values that can't be decoded or upgraded,
+    /// which are reported instead of failing the migration.
+    ///
+    /// # Arguments:
+    ///
+    /// * `root` - root of the subtree
+    /// * `batch_size` - number of values per `commit`
+    ///
+    /// # Returns:
+    ///
+    /// * numbers of values upgraded and the keys skipped
+    pub fn migrate(&self, root: &str, batch_size: usize) -> Result<MigrationReport> {
+        let mut report = MigrationReport::default();
+        for batch in recipes::subtree(&self.client, root)?.chunks(batch_size.max(1)) {
+            let (migrated, skipped) = self.client.options().conflicts.run_optimistic(|_| self.migrate_batch(batch))?;
+            report.migrated += migrated;
+            report.skipped.extend(skipped);
+        }
+        Ok(report)
+    }
+
+    /// Returns the number of values upgraded and the keys skipped, `None` on conflict.
+    fn migrate_batch(&self, keys: &[String]) -> Result<Option<(usize, Vec<String>)>> {
+        let mut upgraded = Vec::new();
+        let mut skipped = Vec::new();
+        for key in keys {
+            let (version, stored) = match self.client.get_bytes(key, false) {
+                Ok((_, stored, _)) if stored.is_empty() => continue,
+                Ok((version, stored, _)) => (version, stored),
+                Err(OffkvError::NoEntry) => continue,
+                Err(error) => return Err(error),
+            };
+
+            if untag(&stored).0 < self.schema.version() {
+                match self.schema.decode(&stored) {
+                    Ok((value, _)) => upgraded.push((key, version, self.schema.encode(&value)?)),
+                    Err(OffkvError::Decode(_)) => skipped.push(key.clone()),
+                    Err(error) => return Err(error),
+                }
+            }
+        }
+
+        if upgraded.is_empty() {
+            return Ok(Some((0, skipped)));
+        }
+
+        match self.client.commit(Transaction{
+            checks: upgraded.iter().map(|(key, version, _)| TxnCheck{key, version: *version}).collect(),
+            ops: upgraded.iter().map(|(key, _, value)| TxnOp::SetBytes{key, value}).collect(),
+        }) {
+            Ok(_) => Ok(Some((upgraded.len(), skipped))),
+            Err(OffkvError::TxnFailed(_)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::codec::Json;
+
+    fn schema() -> Schema<Json, (String, u32, bool)> {
+        let mut schema = Schema::new(2);
+        schema.register(0, |name: String| (name, 0u32)).unwrap();
+        schema.register(1, |(name, age): (String, u32)| (name, age, false)).unwrap();
+        schema
+    }
+
+    #[test]
+    fn current_values_round_trip() {
+        let schema = schema();
+        let stored = schema.encode(&(String::from("ada"), 36, true)).unwrap();
+
+        assert!(stored.starts_with(MAGIC));
+        assert_eq!(schema.decode(&stored).unwrap(), ((String::from("ada"), 36, true), 2));
+    }
+
+    #[test]
+    fn untagged_values_are_upgraded_from_version_0() {
+        assert_eq!(schema().decode(br#""ada""#).unwrap(), ((String::from("ada"), 0, false), 0));
+    }
+
+    #[test]
+    fn tagged_values_are_upgraded_from_their_version() {
+        let mut stored = MAGIC.to_vec();
+        stored.extend_from_slice(&1u32.to_be_bytes());
+        stored.extend_from_slice(br#"["ada",36]"#);
+
+        assert_eq!(schema().decode(&stored).unwrap(), ((String::from("ada"), 36, false), 1));
+    }
+
+    #[test]
+    fn newer_versions_and_gaps_in_the_chain_are_rejected() {
+        let mut stored = MAGIC.to_vec();
+        stored.extend_from_slice(&3u32.to_be_bytes());
+        stored.extend_from_slice(br#"["ada",36,true]"#);
+        assert!(matches!(schema().decode(&stored), Err(OffkvError::Decode(_))));
+
+        let mut gap = Schema::<Json, (String, u32, bool)>::new(2);
+        gap.register(1, |(name, age): (String, u32)| (name, age, false)).unwrap();
+        assert!(matches!(gap.decode(br#""ada""#), Err(OffkvError::Decode(_))));
+    }
+
+    #[test]
+    fn values_failing_an_upgrade_are_rejected() {
+        assert!(matches!(schema().decode(b"42"), Err(OffkvError::Decode(_))));
+    }
+
+    #[test]
+    fn upgrades_from_the_current_version_are_rejected() {
+        let mut schema = schema();
+        assert!(matches!(schema.register(2, |value: (String, u32, bool)| value), Err(OffkvError::InvalidArgument(_))));
+        assert!(matches!(schema.register(3, |value: (String, u32, bool)| value), Err(OffkvError::InvalidArgument(_))));
+    }
+}
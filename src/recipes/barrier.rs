@@ -0,0 +1,294 @@
+use std::time::{Duration,Instant};
+
+use super::*;
+
+use crate::txn::{Transaction,TxnCheck,TxnOp};
+
+
+/// Distributed barrier: participants wait until the gate key is removed.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::Barrier;
+/// use std::{thread,time::Duration};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let barrier = Barrier::new(&client, "/barriers/migration").unwrap();
+/// barrier.set().unwrap();
+///
+/// let worker = thread::spawn(|| {
+///     let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///     let barrier = Barrier::new(&client, "/barriers/migration").unwrap();
+///     assert!(!barrier.wait_timeout(Duration::from_millis(100)).unwrap());
+///     barrier.wait().unwrap();
+/// });
+///
+/// thread::sleep(Duration::from_secs(1));
+/// barrier.remove().unwrap();
+/// worker.join().unwrap();
+///
+/// # client.erase("/barriers", 0);
+/// ```
+pub struct Barrier {
+    client: Client,
+    key: String,
+}
+
+impl Barrier {
+    /// Creates a handle of the barrier with the given gate key, creating
+    /// the gate's ancestors if needed. The barrier is not set.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the barrier is operated with
+    /// * `key` - the gate key, its presence holds the participants
+    pub fn new(client: &Client, key: &str) -> Result<Self> {
+        ensure_path(client, &key[..key.rfind('/').unwrap_or(0)])?;
+        Ok(Barrier{client: client.clone(), key: String::from(key)})
+    }
+
+    /// Sets the barrier, does nothing if it's already set.
+    pub fn set(&self) -> Result<()> {
+        match self.client.create(&self.key, "", false) {
+            Ok(_) | Err(OffkvError::EntryExists) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Removes the barrier releasing the waiting participants,
+    /// does nothing if it isn't set.
+    pub fn remove(&self) -> Result<()> {
+        match self.client.erase(&self.key, 0) {
+            Ok(()) | Err(OffkvError::NoEntry) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Blocks until the barrier is removed.
+    pub fn wait(&self) -> Result<()> {
+        self.wait_within(Wait::Forever).map(|_| ())
+    }
+
+    /// Blocks until the barrier is removed or the timeout elapses.
+    ///
+    /// # Returns:
+    ///
+    /// * `true` if the barrier was removed, `false` on timeout
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.wait_within(Wait::Until(Instant::now() + timeout))
+    }
+
+    fn wait_within(&self, wait: Wait) -> Result<bool> {
+        loop {
+            match self.client.exists(&self.key, true)? {
+                (0, _) => return Ok(true),
+                (_, Some(watch_handle)) => if !wait.on(watch_handle) {
+                    return Ok(false);
+                },
+                (_, None) => unreachable!(),
+            }
+        }
+    }
+}
+
+
+/// Distributed double barrier: participants enter together once `count`
+/// of them are present and leave together once all of them have left.
+///
+/// Participants are leased sequential keys under the barrier key, so a crashed
+/// participant doesn't hold the others on leaving. Each round of the barrier has
+/// its own `ready-<round>` key: the first participant to see `count` participants
+/// of the round creates it together with starting the next round, so participants
+/// entering while the previous round is leaving don't mix with it. The last
+/// participant to leave the round erases its key.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::DoubleBarrier;
+/// use std::thread;
+/// # let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let workers: Vec<_> = (0..3).map(|_| thread::spawn(|| {
+///     let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///     let barrier = DoubleBarrier::new(&client, "/barriers/batch", 3).unwrap();
+///
+///     barrier.enter().unwrap();
+///     // all the workers are here
+///     barrier.leave().unwrap();
+/// })).collect();
+///
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+///
+/// # client.erase("/barriers", 0);
+/// ```
+pub struct DoubleBarrier {
+    client: Client,
+    key: String,
+    count: usize,
+    // the participant's key and the round it joined
+    participant: std::sync::Mutex<Option<(Lease, u64)>>,
+}
+
+const PARTICIPANT_PREFIX: &str = "participant-";
+const READY_PREFIX: &str = "ready-";
+const ROUND: &str = "round";
+
+impl DoubleBarrier {
+    /// Creates a handle of the barrier stored under the key, creating the key
+    /// (and its ancestors) if needed.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the barrier is operated with
+    /// * `key` - key of the barrier
+    /// * `count` - number of participants to wait for on entering
+    pub fn new(client: &Client, key: &str, count: usize) -> Result<Self> {
+        ensure_path(client, key)?;
+        Ok(DoubleBarrier{
+            client: client.clone(),
+            key: String::from(key),
+            count,
+            participant: std::sync::Mutex::new(None),
+        })
+    }
+
+    /// Joins the barrier and blocks until `count` participants have joined.
+    pub fn enter(&self) -> Result<()> {
+        self.enter_within(Wait::Forever).map(|_| ())
+    }
+
+    /// Joins the barrier and blocks until `count` participants have joined
+    /// or the timeout elapses, in which case the participant withdraws.
+    ///
+    /// # Returns:
+    ///
+    /// * `true` if entered, `false` on timeout
+    pub fn enter_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.enter_within(Wait::Until(Instant::now() + timeout))
+    }
+
+    /// Leaves the barrier and blocks until all the participants of the round have left.
+    pub fn leave(&self) -> Result<()> {
+        self.leave_within(Wait::Forever).map(|_| ())
+    }
+
+    /// Leaves the barrier and blocks until all the participants of the round have left
+    /// or the timeout elapses.
+    ///
+    /// # Returns:
+    ///
+    /// * `true` if everybody left, `false` on timeout
+    pub fn leave_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.leave_within(Wait::Until(Instant::now() + timeout))
+    }
+
+    fn enter_within(&self, wait: Wait) -> Result<bool> {
+        loop {
+            let round = self.join()?;
+            let ready = self.ready(round);
+
+            let (version, watch_handle) = self.client.exists(&ready, true)?;
+            if version != 0 {
+                return Ok(true);
+            }
+
+            let (round_version, current) = self.round()?;
+            if current != round {
+                // the ready key is created before the round moves on
+                if self.client.exists(&ready, false)?.0 != 0 {
+                    return Ok(true);
+                }
+                // joined after the round was over, join the next one
+                self.withdraw();
+                continue;
+            }
+
+            if self.participants(round)?.len() >= self.count {
+                self.open(round, round_version)?;
+                continue;
+            }
+
+            if !wait.on(watch_handle.unwrap()) {
+                self.withdraw();
+                return Ok(false);
+            }
+        }
+    }
+
+    fn leave_within(&self, wait: Wait) -> Result<bool> {
+        let round = match self.participant.lock().unwrap().take() {
+            Some((_, round)) => round,
+            None => return Ok(true),
+        };
+
+        loop {
+            let (_, watch_handle) = self.client.get_children(&self.key, true)?;
+
+            if self.participants(round)?.is_empty() {
+                return match self.client.erase(&self.ready(round), 0) {
+                    Ok(()) | Err(OffkvError::NoEntry) => Ok(true),
+                    Err(error) => Err(error),
+                };
+            }
+
+            if !wait.on(watch_handle.unwrap()) {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Returns the round the participant is in, joining the current one if needed.
+    fn join(&self) -> Result<u64> {
+        let mut participant = self.participant.lock().unwrap();
+        if let Some((_, round)) = *participant {
+            return Ok(round);
+        }
+
+        let (_, round) = self.round()?;
+        let prefix = format!("{}{}-", PARTICIPANT_PREFIX, round);
+        let (key, version, _) = create_sequential(&self.client, &self.key, &prefix, b"", true)?;
+        *participant = Some((Lease::new(&self.client, key, version), round));
+        Ok(round)
+    }
+
+    fn withdraw(&self) {
+        let participant = self.participant.lock().unwrap().take();
+        drop(participant);
+    }
+
+    /// Returns the version of the round key and the current round.
+    fn round(&self) -> Result<(i64, u64)> {
+        match self.client.get(&format!("{}/{}", self.key, ROUND), false) {
+            Ok((version, round, _)) => round.parse().map(|round| (version, round))
+                .map_err(|_| OffkvError::Decode(String::from("the barrier round is not a number"))),
+            Err(OffkvError::NoEntry) => Ok((0, 0)),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn ready(&self, round: u64) -> String {
+        format!("{}/{}{}", self.key, READY_PREFIX, round)
+    }
+
+    fn participants(&self, round: u64) -> Result<Vec<String>> {
+        sequential_children(&self.client, &self.key, &format!("{}{}-", PARTICIPANT_PREFIX, round))
+    }
+
+    /// Creates the ready key of the round and starts the next one,
+    /// unless someone else has done it.
+    fn open(&self, round: u64, version: i64) -> Result<()> {
+        let (ready, round_key, next) = (self.ready(round), format!("{}/{}", self.key, ROUND), (round + 1).to_string());
+        let (checks, next_round) = match version {
+            0 => (vec![], TxnOp::Create{key: &round_key, value: &next, leased: false}),
+            version => (vec![TxnCheck{key: &round_key, version}], TxnOp::Set{key: &round_key, value: &next}),
+        };
+
+        match self.client.commit(Transaction{checks, ops: vec![TxnOp::Create{key: &ready, value: "", leased: false}, next_round]}) {
+            Ok(_) | Err(OffkvError::TxnFailed(_)) | Err(OffkvError::OutcomeUnknown) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+}
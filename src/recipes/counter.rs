@@ -0,0 +1,187 @@
+use std::convert::TryFrom;
+
+use super::*;
+
+
+/// Distributed counter stored as a decimal number in the counter key.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::Counter;
+/// use rsoffkv::result::OffkvError;
+/// use std::thread;
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let workers: Vec<_> = (0..4).map(|_| thread::spawn(|| {
+///     let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///     let counter = Counter::new(&client, "/counters/visits").unwrap();
+///     for _ in 0..10 {
+///         counter.add(1).unwrap();
+///     }
+/// })).collect();
+///
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+///
+/// let counter = Counter::new(&client, "/counters/visits").unwrap();
+/// assert_eq!(counter.get().unwrap(), 40);
+/// assert_eq!(counter.add(-30).unwrap(), 10);
+///
+/// counter.reset().unwrap();
+/// assert_eq!(counter.get().unwrap(), 0);
+///
+/// // the counter doesn't wrap around
+/// counter.add(i64::MAX).unwrap();
+/// assert!(matches!(counter.add(1), Err(OffkvError::Overflow)));
+///
+/// # client.erase("/counters", 0);
+/// ```
+pub struct Counter {
+    client: Client,
+    key: String,
+}
+
+/// Parses the value of the counter key, a missing or empty value counts as 0.
+fn parse(value: Option<&[u8]>) -> Result<i64> {
+    match value {
+        None | Some(b"") => Ok(0),
+        Some(value) => std::str::from_utf8(value).ok().and_then(|value| value.parse().ok())
+            .ok_or_else(|| OffkvError::Decode(String::from("the counter is not a number"))),
+    }
+}
+
+impl Counter {
+    /// Creates a handle of the counter stored under the key, creating the key's
+    /// ancestors if needed. A missing key counts as 0.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the counter is operated with
+    /// * `key` - key of the counter
+    pub fn new(client: &Client, key: &str) -> Result<Self> {
+        ensure_path(client, &key[..key.rfind('/').unwrap_or(0)])?;
+        Ok(Counter{client: client.clone(), key: String::from(key)})
+    }
+
+    /// Returns the current value.
+    ///
+    /// # Returns:
+    ///
+    /// * the value
+    /// * `OffkvError::Decode` if the key holds something else than a number
+    pub fn get(&self) -> Result<i64> {
+        match self.client.get_bytes(&self.key, false) {
+            Ok((_, value, _)) => parse(Some(&value)),
+            Err(OffkvError::NoEntry) => Ok(0),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Atomically adds the delta, see `Client::update` for the handling of conflicts.
+    ///
+    /// # Returns:
+    ///
+    /// * the new value
+    /// * `OffkvError::Decode` if the key holds something else than a number
+    /// * `OffkvError::Overflow` if the new value is out of the range of `i64`
+    pub fn add(&self, delta: i64) -> Result<i64> {
+        let mut new = 0;
+        self.client.try_update(&self.key, |old| {
+            new = parse(old)?.checked_add(delta).ok_or(OffkvError::Overflow)?;
+            Ok(Some(new.to_string().into_bytes()))
+        })?;
+        Ok(new)
+    }
+
+    /// Sets the counter to 0.
+    ///
+    /// Must not be used on the key of an `IdAllocator`: the IDs handed out
+    /// before would be handed out again.
+    pub fn reset(&self) -> Result<()> {
+        self.client.set(&self.key, "0").map(|_| ())
+    }
+}
+
+
+/// Allocator of globally unique positive IDs.
+///
+/// IDs are reserved from a shared `Counter` in blocks, so an allocator goes
+/// to the store once per `block` IDs. IDs are unique across all the allocators
+/// sharing the counter key, but not ordered across them, and the unused rest
+/// of a block is lost when the allocator is dropped. The counter key must not be
+/// written by anything else, in particular not reset.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::IdAllocator;
+/// use std::collections::HashSet;
+/// use std::thread;
+/// # let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let workers: Vec<_> = (0..4).map(|_| thread::spawn(|| {
+///     let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///     let ids = IdAllocator::new(&client, "/counters/ids", 10).unwrap();
+///     (0..25).map(|_| ids.next_id().unwrap()).collect::<Vec<_>>()
+/// })).collect();
+///
+/// let mut allocated = HashSet::new();
+/// for worker in workers {
+///     for id in worker.join().unwrap() {
+///         // no duplicates
+///         assert!(allocated.insert(id));
+///     }
+/// }
+/// assert_eq!(allocated.len(), 100);
+///
+/// # client.erase("/counters", 0);
+/// ```
+pub struct IdAllocator {
+    counter: Counter,
+    block: u64,
+    // next ID to hand out and the end of the reserved block
+    reserved: std::sync::Mutex<(u64, u64)>,
+}
+
+impl IdAllocator {
+    /// Creates an allocator reserving IDs from the counter stored under the key.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the counter is operated with
+    /// * `key` - key of the counter
+    /// * `block` - number of IDs reserved at once
+    pub fn new(client: &Client, key: &str, block: u64) -> Result<Self> {
+        Ok(IdAllocator{
+            counter: Counter::new(client, key)?,
+            block: block.max(1),
+            reserved: std::sync::Mutex::new((0, 0)),
+        })
+    }
+
+    /// Returns a new ID, reserving the next block if the current one is used up.
+    ///
+    /// # Returns:
+    ///
+    /// * the ID
+    /// * `OffkvError::Decode` if the counter key holds something else than a non-negative number
+    /// * `OffkvError::Overflow` if the IDs are used up
+    pub fn next_id(&self) -> Result<u64> {
+        let mut reserved = self.reserved.lock().unwrap();
+
+        if reserved.0 == reserved.1 {
+            let block = i64::try_from(self.block).map_err(|_| OffkvError::Overflow)?;
+            // the counter holds the last ID reserved
+            let last = u64::try_from(self.counter.add(block)?).ok()
+                .filter(|last| *last >= self.block)
+                .ok_or_else(|| OffkvError::Decode(String::from("the counter was negative")))?;
+            *reserved = (last + 1 - self.block, last + 1);
+        }
+
+        let id = reserved.0;
+        reserved.0 += 1;
+        Ok(id)
+    }
+}
@@ -0,0 +1,351 @@
+use std::sync::{Arc,Condvar,Weak,mpsc};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::{Duration,Instant};
+
+use super::*;
+
+use crate::backoff::Backoff;
+use crate::client::SessionEvent;
+
+
+/// Change of the leadership observed by a `LeaderElection`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeaderEvent {
+    /// identity of the current leader, `None` if there are no candidates
+    /// (or the leader is unknown because the connection was lost)
+    pub leader: Option<String>,
+
+    /// whether this participant is the leader
+    pub leading: bool,
+}
+
+/// Leader election among the participants sharing the election key.
+///
+/// A campaigning participant creates a leased sequential key holding its identity;
+/// the candidate with the lowest sequence number leads. The participant steps down
+/// as soon as the connection is lost, and campaigns again once the client
+/// reconnects (see `ClientOptions::reconnect`). Dropping the election resigns.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::LeaderElection;
+/// use std::time::Duration;
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let another_client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let first = LeaderElection::new(&client, "/elections/scheduler", "node-1").unwrap();
+/// let second = LeaderElection::new(&another_client, "/elections/scheduler", "node-2").unwrap();
+/// let events = second.events();
+///
+/// first.wait_for_leadership().unwrap();
+/// assert!(!second.wait_for_leadership_timeout(Duration::from_millis(500)).unwrap());
+///
+/// first.resign();
+/// second.wait_for_leadership().unwrap();
+/// assert!(second.is_leader());
+///
+/// // the last event reports the new leader
+/// let event = events.iter().find(|event| event.leading).unwrap();
+/// assert_eq!(event.leader, Some(String::from("node-2")));
+///
+/// # drop(second);
+/// # client.erase("/elections", 0);
+/// ```
+pub struct LeaderElection {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    client: Client,
+    key: String,
+    identity: String,
+    state: std::sync::Mutex<State>,
+    changed: Condvar,
+    listeners: std::sync::Mutex<Vec<mpsc::Sender<LeaderEvent>>>,
+    stopped: AtomicBool,
+    // wakes the observer up
+    wakeup: mpsc::Sender<()>,
+}
+
+struct State {
+    campaigning: bool,
+    candidacy: Option<Lease>,
+    leader: Option<String>,
+    leading: bool,
+    // bumped on every session event, so that leadership read before one is dropped
+    generation: u64,
+}
+
+const CANDIDATE_PREFIX: &str = "candidate-";
+
+impl LeaderElection {
+    /// Joins the election as an observer, see `campaign` to become a candidate.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the election is operated with
+    /// * `key` - key of the election
+    /// * `identity` - identity of the participant, reported to the others when it leads
+    pub fn new(client: &Client, key: &str, identity: &str) -> Result<Self> {
+        ensure_path(client, key)?;
+        let (wakeup, wakeups) = mpsc::channel();
+
+        let shared = Arc::new(Shared{
+            client: client.clone(),
+            key: String::from(key),
+            identity: String::from(identity),
+            state: std::sync::Mutex::new(State{campaigning: false, candidacy: None, leader: None, leading: false, generation: 0}),
+            changed: Condvar::new(),
+            listeners: std::sync::Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+            wakeup,
+        });
+
+        let (observer, session_events) = (Arc::downgrade(&shared), client.session_events());
+        let observer_client = client.clone();
+        thread::Builder::new()
+            .name(String::from("rsoffkv-election"))
+            .spawn(move || Shared::observe(observer_client, observer, wakeups))
+            .expect("Failed to spawn election observer");
+
+        let watcher = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name(String::from("rsoffkv-election-session"))
+            .spawn(move || Shared::follow_session(watcher, session_events))
+            .expect("Failed to spawn election session watcher");
+
+        Ok(LeaderElection{shared})
+    }
+
+    /// Becomes a candidate (does nothing if already campaigning).
+    pub fn campaign(&self) -> Result<()> {
+        self.shared.campaign()
+    }
+
+    /// Campaigns and blocks until the participant leads.
+    pub fn wait_for_leadership(&self) -> Result<()> {
+        self.shared.campaign()?;
+
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.leading {
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Campaigns and blocks until the participant leads or the timeout elapses.
+    ///
+    /// # Returns:
+    ///
+    /// * `true` if the participant leads, `false` on timeout
+    pub fn wait_for_leadership_timeout(&self, timeout: Duration) -> Result<bool> {
+        self.shared.campaign()?;
+        let deadline = Instant::now() + timeout;
+
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.leading {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Ok(false);
+            }
+            state = self.shared.changed.wait_timeout(state, remaining).unwrap().0;
+        }
+        Ok(true)
+    }
+
+    /// Returns whether the participant leads at the moment.
+    pub fn is_leader(&self) -> bool {
+        self.shared.state.lock().unwrap().leading
+    }
+
+    /// Returns the identity of the current leader.
+    pub fn leader(&self) -> Option<String> {
+        self.shared.state.lock().unwrap().leader.clone()
+    }
+
+    /// Subscribes to leadership changes; the current state is delivered first.
+    pub fn events(&self) -> mpsc::Receiver<LeaderEvent> {
+        let (sender, receiver) = mpsc::channel();
+
+        let state = self.shared.state.lock().unwrap();
+        let _ = sender.send(LeaderEvent{leader: state.leader.clone(), leading: state.leading});
+        self.shared.listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    /// Returns the key and version of the candidacy while the participant leads,
+    /// to fence the leader's writes with a `TxnCheck`.
+    pub(crate) fn candidacy(&self) -> Option<(String, i64)> {
+        let state = self.shared.state.lock().unwrap();
+        match (state.leading, &state.candidacy) {
+            (true, Some(lease)) => Some((lease.key.clone(), lease.version)),
+            _ => None,
+        }
+    }
+
+    /// Stops campaigning, stepping down if leading.
+    pub fn resign(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.campaigning = false;
+        state.candidacy = None;
+        let leader = state.leader.clone();
+        self.shared.update(&mut state, leader, false);
+    }
+}
+
+impl Drop for LeaderElection {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.campaigning = false;
+            state.candidacy = None;
+        }
+
+        // wake the observer up so it notices the stop
+        let _ = self.shared.wakeup.send(());
+    }
+}
+
+impl Shared {
+    fn campaign(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.campaigning = true;
+
+        if state.candidacy.is_none() {
+            let (key, version, _) = create_sequential(
+                &self.client, &self.key, CANDIDATE_PREFIX, self.identity.as_bytes(), true)?;
+            state.candidacy = Some(Lease::new(&self.client, key, version));
+        }
+        Ok(())
+    }
+
+    /// Records the observed leadership notifying the subscribers if it changed.
+    fn update(&self, state: &mut State, leader: Option<String>, leading: bool) {
+        if state.leader == leader && state.leading == leading {
+            return;
+        }
+
+        state.leader = leader.clone();
+        state.leading = leading;
+        self.changed.notify_all();
+
+        let event = LeaderEvent{leader, leading};
+        self.listeners.lock().unwrap().retain(|listener| listener.send(event.clone()).is_ok());
+    }
+
+    /// Watches the candidates until the election is dropped.
+    fn observe(client: Client, shared: Weak<Shared>, wakeups: mpsc::Receiver<()>) {
+        let mut failures = 0;
+
+        loop {
+            let shared = match shared.upgrade() {
+                Some(shared) if !shared.stopped.load(Ordering::SeqCst) => shared,
+                _ => return,
+            };
+
+            match shared.leadership(&client) {
+                Ok(Some(watch_handle)) => {
+                    failures = 0;
+                    let wakeup = shared.wakeup.clone();
+                    drop(shared);
+                    watch_handle.notify(wakeup, (), ());
+                    let _ = wakeups.recv();
+                },
+                // the leader left or the session changed in between, look again
+                Ok(None) => {},
+                Err(_) => {
+                    {
+                        let mut state = shared.state.lock().unwrap();
+                        shared.update(&mut state, None, false);
+                    }
+                    drop(shared);
+                    let _ = wakeups.recv_timeout(Backoff::default().delay(failures));
+                    failures += 1;
+                },
+            }
+        }
+    }
+
+    /// Determines the current leader, returns a watch for the candidates
+    /// or `None` to look again.
+    fn leadership<'a>(&self, client: &'a Client) -> Result<Option<WatchHandle<'a>>> {
+        // a candidacy created after this may be missing from the candidates read below
+        let (known, generation) = {
+            let state = self.state.lock().unwrap();
+            (state.candidacy.as_ref().map(|lease| lease.key.clone()), state.generation)
+        };
+
+        let (_, watch_handle) = client.get_children(&self.key, true)?;
+        let candidates = sequential_children(client, &self.key, CANDIDATE_PREFIX)?;
+
+        let (leader, leader_version) = match candidates.first() {
+            Some(first) => match client.get(first, false) {
+                Ok((version, identity, _)) => (Some((first.clone(), identity)), version),
+                Err(OffkvError::NoEntry) => return Ok(None),
+                Err(error) => return Err(error),
+            },
+            None => (None, 0),
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        // the read may predate e.g. stepping down on suspension, look again
+        if state.generation != generation {
+            return Ok(None);
+        }
+
+        // the candidacy is gone if its key is (or was recreated in another session)
+        let lost = match state.candidacy {
+            Some(ref lease) if known.as_ref() == Some(&lease.key) => !candidates.contains(&lease.key)
+                || leader.as_ref().is_some_and(|(key, _)| *key == lease.key && leader_version != lease.version),
+            _ => false,
+        };
+        if lost {
+            if let Some(lease) = state.candidacy.take() {
+                lease.disarm();
+            }
+        }
+
+        let leading = match (&state.candidacy, &leader) {
+            (Some(lease), Some((key, _))) => lease.key == *key,
+            _ => false,
+        };
+        self.update(&mut state, leader.map(|(_, identity)| identity), leading);
+
+        Ok(watch_handle)
+    }
+
+    /// Steps down on connection loss and campaigns again after reconnection.
+    fn follow_session(shared: Weak<Shared>, events: mpsc::Receiver<SessionEvent>) {
+        loop {
+            let event = events.recv_timeout(Duration::from_secs(1));
+
+            let shared = match shared.upgrade() {
+                Some(shared) if !shared.stopped.load(Ordering::SeqCst) => shared,
+                _ => return,
+            };
+
+            let mut state = shared.state.lock().unwrap();
+            if event.is_ok() {
+                state.generation += 1;
+            }
+            match event {
+                Ok(SessionEvent::Suspended) => shared.update(&mut state, None, false),
+                Ok(SessionEvent::Expired) => if let Some(lease) = state.candidacy.take() {
+                    lease.disarm();
+                },
+                Ok(SessionEvent::Reconnected) if state.campaigning => {
+                    drop(state);
+                    let _ = shared.campaign();
+                },
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                _ => {},
+            }
+        }
+    }
+}
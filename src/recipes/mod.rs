@@ -0,0 +1,210 @@
+//! Coordination recipes built on top of `Client`.
+//!
+//! All recipes rely on leased keys: a participant that crashes or loses its session
+//! leaves automatically. Waiting is done with watches, each participant watching
+//! as few keys as possible to avoid the thundering herd.
+
+use std::time::Instant;
+
+use crate::client::{Client,WatchHandle};
+use crate::result::OffkvError;
+
+mod barrier;
+mod counter;
+mod leader;
+mod mutex;
+mod queue;
+mod ratelimit;
+mod registry;
+mod rwlock;
+mod semaphore;
+mod shards;
+
+pub use barrier::{Barrier,DoubleBarrier};
+pub use counter::{Counter,IdAllocator};
+pub use leader::{LeaderElection,LeaderEvent};
+pub use mutex::{Mutex,MutexGuard};
+pub use queue::{Job,Queue,QueueOptions};
+pub use ratelimit::{RateLimiter,RateLimiterOptions};
+pub use registry::{Health,Membership,Registration,ServiceInstance,ServiceRegistry};
+pub use rwlock::{RwLock,RwLockReadGuard,RwLockWriteGuard};
+pub use semaphore::{Semaphore,SemaphorePermit};
+pub use shards::ShardAssigner;
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// How long an acquisition may block.
+#[derive(Clone, Copy)]
+pub(crate) enum Wait {
+    Forever,
+    Until(Instant),
+    Never,
+}
+
+impl Wait {
+    /// Waits for the watch to fire, returns `false` if it didn't in time.
+    pub(crate) fn on(self, watch_handle: WatchHandle) -> bool {
+        match self {
+            Wait::Forever => {
+                watch_handle.wait();
+                true
+            },
+            Wait::Until(deadline) =>
+                watch_handle.wait_timeout(deadline.saturating_duration_since(Instant::now())),
+            Wait::Never => false,
+        }
+    }
+}
+
+
+/// Leased key held by a participant of a recipe, erased on drop.
+pub(crate) struct Lease {
+    client: Client,
+    key: String,
+    version: i64,
+    armed: bool,
+}
+
+impl Lease {
+    pub(crate) fn new(client: &Client, key: String, version: i64) -> Self {
+        Lease{client: client.clone(), key, version, armed: true}
+    }
+
+    /// Gives the lease up without erasing the key, e.g. once it's known to be lost.
+    pub(crate) fn disarm(mut self) {
+        self.armed = false;
+    }
+
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns `OffkvError::LeaseLost` if the key is gone or was recreated
+    /// in another session (see `ClientOptions::recreate_leased`).
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.client.exists(&self.key, false)? {
+            (version, _) if version == self.version => Ok(()),
+            _ => Err(OffkvError::LeaseLost),
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if self.armed {
+            let _ = self.client.erase(&self.key, self.version);
+        }
+    }
+}
+
+
+/// Creates a leased sequential key `<parent>/<prefix><number>` and waits until
+/// `blocker`, given all the sequential children of the parent in order and
+/// the position of the created key, returns no key to wait for.
+///
+/// Returns the lease together with the version of the sequence counter set on queueing up
+/// or `None` if the wait has timed out.
+pub(crate) fn queue_up<F>(client: &Client, parent: &str, prefix: &str, value: &[u8], wait: Wait, mut blocker: F)
+    -> Result<Option<(Lease, i64)>>
+    where F: FnMut(&[String], usize) -> Result<Option<String>> {
+
+    let (key, version, token) = create_sequential(client, parent, prefix, value, true)?;
+    let lease = Lease::new(client, key, version);
+
+    loop {
+        let queue = sequential_children(client, parent, "")?;
+        let position = match queue.iter().position(|queued| *queued == lease.key) {
+            Some(position) => position,
+            None => return Err(OffkvError::LeaseLost),
+        };
+
+        let blocking = match blocker(&queue, position)? {
+            Some(blocking) => blocking,
+            None => return Ok(Some((lease, token))),
+        };
+
+        if let Wait::Never = wait {
+            return Ok(None);
+        }
+        if !wait_for_change(client, &blocking, wait)? {
+            return Ok(None);
+        }
+    }
+}
+
+/// Creates the key and all its missing ancestors with empty values.
+pub(crate) fn ensure_path(client: &Client, key: &str) -> Result<()> {
+    let mut end = 0;
+    while end < key.len() {
+        end = key[end + 1..].find('/').map_or(key.len(), |position| end + 1 + position);
+
+        match client.create(&key[..end], "", false) {
+            Ok(_) | Err(OffkvError::EntryExists) => {},
+            // the key may hold another value, it's only needed to exist
+            Err(OffkvError::OutcomeUnknown) if client.exists(&key[..end], false)?.0 != 0 => {},
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(())
+}
+
+/// Creates a sequential child of the parent, see `Client::create_sequential`.
+///
+/// # Returns:
+///
+/// * the created key, its version and the version of the sequence counter after the increment
+pub(crate) fn create_sequential(client: &Client, parent: &str, prefix: &str, value: &[u8], leased: bool)
+    -> Result<(String, i64, i64)> {
+
+    client.create_sequential_versioned(&format!("{}/{}", parent, prefix), value, leased)
+}
+
+/// Returns the sequence number of a key created by `create_sequential`.
+pub(crate) fn sequence_number(key: &str) -> Option<u64> {
+    let name = &key[key.rfind('/').map_or(0, |position| position + 1)..];
+    match name.len() {
+        n if n >= 10 => name[n - 10..].parse().ok(),
+        _ => None,
+    }
+}
+
+/// Returns children of the key with the given name prefix ordered by their sequence numbers.
+pub(crate) fn sequential_children(client: &Client, parent: &str, prefix: &str) -> Result<Vec<String>> {
+    let (children, _) = client.get_children(parent, false)?;
+    let name_start = parent.len() + 1;
+
+    let mut children: Vec<(u64, String)> = children.into_iter()
+        .filter(|child| child.len() > name_start && child[name_start..].starts_with(prefix))
+        .filter_map(|child| sequence_number(&child).map(|number| (number, child)))
+        .collect();
+    children.sort();
+
+    Ok(children.into_iter().map(|(_, child)| child).collect())
+}
+
+/// Returns the key and all its descendants, parents before their children.
+#[cfg(any(feature = "encryption", feature = "json", feature = "cbor", feature = "msgpack", feature = "bincode"))]
+pub(crate) fn subtree(client: &Client, root: &str) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut pending = vec![String::from(root)];
+    while let Some(key) = pending.pop() {
+        match client.get_children(&key, false) {
+            Ok((children, _)) => pending.extend(children),
+            Err(OffkvError::NoEntry) => continue,
+            Err(error) => return Err(error),
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Waits until the key changes or disappears, returns `false` on timeout.
+pub(crate) fn wait_for_change(client: &Client, key: &str, wait: Wait) -> Result<bool> {
+    match client.exists(key, true)? {
+        (0, _) => Ok(true),
+        (_, Some(watch_handle)) => Ok(wait.on(watch_handle)),
+        (_, None) => unreachable!(),
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::{Duration,Instant};
+
+use super::*;
+
+
+/// Distributed mutex.
+///
+/// Each contender creates a leased sequential key under the mutex key and waits
+/// for the deletion of its immediate predecessor only, so a release wakes up
+/// exactly one waiter. The lock is released when the guard is dropped or
+/// the holder's session expires.
+///
+/// The fencing token of a holder is the version of the mutex's sequence counter
+/// (`<key>/.seq`, see `Client::create_sequential`) set when it queued up,
+/// not a version of the mutex key itself.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::Mutex;
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let mutex = Mutex::new(&client, "/locks/reindex").unwrap();
+///
+/// {
+///     let guard = mutex.lock().unwrap();
+///
+///     // nobody else can take it now
+///     let another_client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///     let another_mutex = Mutex::new(&another_client, "/locks/reindex").unwrap();
+///     assert!(another_mutex.try_lock().unwrap().is_none());
+///
+///     // protect downstream writes with the fencing token
+///     guard.check().unwrap();
+///     client.set("/reindexed_by", guard.token().to_string()).unwrap();
+/// }
+///
+/// // the guard is dropped, so the mutex is free again
+/// let token = mutex.lock().unwrap().token();
+/// assert!(token > 0);
+///
+/// # client.erase("/locks", 0);
+/// # client.erase("/reindexed_by", 0);
+/// ```
+pub struct Mutex {
+    client: Client,
+    key: String,
+}
+
+/// Held lock of a `Mutex`, releases it on drop.
+pub struct MutexGuard<'a> {
+    _mutex: &'a Mutex,
+    lease: Lease,
+    token: i64,
+}
+
+impl Mutex {
+    /// Creates a handle of the mutex stored under the key, creating the key
+    /// (and its ancestors) if needed.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the mutex is operated with
+    /// * `key` - key of the mutex
+    pub fn new(client: &Client, key: &str) -> Result<Self> {
+        ensure_path(client, key)?;
+        Ok(Mutex{client: client.clone(), key: String::from(key)})
+    }
+
+    /// Acquires the mutex, blocking until it's available.
+    pub fn lock(&self) -> Result<MutexGuard<'_>> {
+        self.acquire(Wait::Forever).map(Option::unwrap)
+    }
+
+    /// Acquires the mutex if it's available right now.
+    ///
+    /// # Returns:
+    ///
+    /// * guard of the mutex or `None` if it's held by someone else
+    pub fn try_lock(&self) -> Result<Option<MutexGuard<'_>>> {
+        self.acquire(Wait::Never)
+    }
+
+    /// Acquires the mutex, blocking at most for the given time.
+    ///
+    /// # Returns:
+    ///
+    /// * guard of the mutex or `None` on timeout
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<Option<MutexGuard<'_>>> {
+        self.acquire(Wait::Until(Instant::now() + timeout))
+    }
+
+    fn acquire(&self, wait: Wait) -> Result<Option<MutexGuard<'_>>> {
+        // wait for the immediate predecessor
+        let acquired = queue_up(&self.client, &self.key, "lock-", b"", wait, |queue, position|
+            Ok(position.checked_sub(1).map(|predecessor| queue[predecessor].clone())))?;
+
+        Ok(acquired.map(|(lease, token)| MutexGuard{_mutex: self, lease, token}))
+    }
+}
+
+impl MutexGuard<'_> {
+    /// Returns the fencing token: the version of the mutex's sequence counter
+    /// set when this holder queued up. Tokens grow with each acquisition, so a downstream
+    /// service may reject writes carrying a token lower than one it has already seen.
+    pub fn token(&self) -> i64 {
+        self.token
+    }
+
+    /// Returns the leased key representing the lock.
+    pub fn key(&self) -> &str {
+        self.lease.key()
+    }
+
+    /// Checks that the lock is still held.
+    ///
+    /// # Returns:
+    ///
+    /// * `OffkvError::LeaseLost` if the lock key is gone (or was recreated
+    ///   in another session, see `ClientOptions::recreate_leased`)
+    pub fn check(&self) -> Result<()> {
+        self.lease.check()
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::BTreeSet;
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+
+use super::*;
+
+use crate::txn::{Transaction,TxnCheck,TxnOp,TxnOpResult};
+
+
+/// Queue settings, see `Queue::with_options`.
+#[derive(Clone, Debug)]
+pub struct QueueOptions {
+    /// how long a popped job stays invisible to other consumers; once it elapses
+    /// the job is delivered again even if its consumer is still alive
+    pub visibility_timeout: Duration,
+
+    /// number of deliveries after which a job that wasn't acknowledged
+    /// is moved to the dead letters
+    pub max_attempts: u32,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions{
+            visibility_timeout: Duration::from_secs(30),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Distributed FIFO queue with at-least-once delivery.
+///
+/// Jobs are persistent sequential keys under the queue key. Popping a job creates
+/// a leased claim key next to it, so the job is delivered again if its consumer
+/// releases it, crashes or exceeds the visibility timeout (consumers' clocks
+/// are assumed to be roughly in sync). Each delivery is counted in the job,
+/// and jobs delivered `max_attempts` times without acknowledgement are moved
+/// to the dead letters. Waiting consumers watch the children of the queue key.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::{Queue,QueueOptions};
+/// use std::time::Duration;
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let queue = Queue::with_options(&client, "/queues/mail", QueueOptions{
+///     visibility_timeout: Duration::from_millis(200),
+///     max_attempts: 2,
+/// }).unwrap();
+///
+/// queue.push("welcome").unwrap();
+/// queue.push("digest").unwrap();
+///
+/// // a released job is delivered again
+/// let job = queue.pop().unwrap();
+/// assert_eq!((job.value(), job.attempts()), (&b"welcome"[..], 1));
+/// job.release();
+///
+/// let job = queue.pop().unwrap();
+/// assert_eq!((job.value(), job.attempts()), (&b"welcome"[..], 2));
+/// job.ack().unwrap();
+///
+/// // so is a job whose consumer got stuck
+/// let job = queue.pop().unwrap();
+/// assert_eq!(queue.peek().unwrap(), None);
+/// std::mem::forget(job);
+///
+/// let job = queue.pop_timeout(Duration::from_secs(5)).unwrap().unwrap();
+/// assert_eq!((job.value(), job.attempts()), (&b"digest"[..], 2));
+/// job.release();
+///
+/// // attempts are exhausted
+/// assert!(queue.try_pop().unwrap().is_none());
+/// assert_eq!(queue.dead_letters().unwrap().len(), 1);
+///
+/// # client.erase("/queues", 0);
+/// ```
+pub struct Queue {
+    client: Client,
+    key: String,
+    options: QueueOptions,
+}
+
+/// Job delivered by `Queue::pop`, released (i.e. delivered again later) on drop
+/// unless acknowledged.
+pub struct Job<'a> {
+    _queue: &'a Queue,
+    claim: Lease,
+    item: String,
+    value: Vec<u8>,
+    attempts: u32,
+}
+
+const ITEM_PREFIX: &str = "item-";
+const CLAIM_PREFIX: &str = "claim-";
+const DEAD: &str = "dead";
+
+/// Prepends the number of deliveries to the job's value.
+fn encode(attempts: u32, value: &[u8]) -> Vec<u8> {
+    let mut encoded = attempts.to_be_bytes().to_vec();
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+fn decode(encoded: &[u8]) -> (u32, &[u8]) {
+    match encoded.len() {
+        n if n >= 4 => (u32::from_be_bytes([encoded[0], encoded[1], encoded[2], encoded[3]]), &encoded[4..]),
+        _ => (0, encoded),
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+impl Queue {
+    /// Creates a handle of the queue stored under the key with default options,
+    /// creating the key (and its ancestors) if needed.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the queue is operated with
+    /// * `key` - key of the queue
+    pub fn new(client: &Client, key: &str) -> Result<Self> {
+        Queue::with_options(client, key, QueueOptions::default())
+    }
+
+    /// Creates a handle of the queue stored under the key.
+    pub fn with_options(client: &Client, key: &str, options: QueueOptions) -> Result<Self> {
+        ensure_path(client, &format!("{}/{}", key, DEAD))?;
+        Ok(Queue{client: client.clone(), key: String::from(key), options})
+    }
+
+    /// Appends a job to the queue.
+    ///
+    /// # Returns:
+    ///
+    /// * key of the job
+    pub fn push<V: AsRef<[u8]>>(&self, value: V) -> Result<String> {
+        create_sequential(&self.client, &self.key, ITEM_PREFIX, &encode(0, value.as_ref()), false)
+            .map(|(key, _, _)| key)
+    }
+
+    /// Returns the value of the job `pop` would deliver, without claiming it.
+    pub fn peek(&self) -> Result<Option<Vec<u8>>> {
+        let (children, _) = self.client.get_children(&self.key, false)?;
+        let claims = self.claims(&children);
+
+        for item in self.items(&children) {
+            if self.claimed(&claims, &item, &mut None)? {
+                continue;
+            }
+            match self.client.get_bytes(&item, false) {
+                Ok((_, value, _)) => return Ok(Some(decode(&value).1.to_vec())),
+                Err(OffkvError::NoEntry) => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Takes the first available job, blocking until there is one.
+    pub fn pop(&self) -> Result<Job<'_>> {
+        self.acquire(Wait::Forever).map(Option::unwrap)
+    }
+
+    /// Takes the first available job if there is one right now.
+    pub fn try_pop(&self) -> Result<Option<Job<'_>>> {
+        self.acquire(Wait::Never)
+    }
+
+    /// Takes the first available job, blocking at most for the given time.
+    ///
+    /// # Returns:
+    ///
+    /// * the job or `None` on timeout
+    pub fn pop_timeout(&self, timeout: Duration) -> Result<Option<Job<'_>>> {
+        self.acquire(Wait::Until(Instant::now() + timeout))
+    }
+
+    /// Returns keys and values of the jobs moved to the dead letters.
+    pub fn dead_letters(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let dead = format!("{}/{}", self.key, DEAD);
+        let mut letters = Vec::new();
+
+        for letter in sequential_children(&self.client, &dead, ITEM_PREFIX)? {
+            match self.client.get_bytes(&letter, false) {
+                Ok((_, value, _)) => letters.push((letter, value)),
+                Err(OffkvError::NoEntry) => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(letters)
+    }
+
+    fn acquire(&self, wait: Wait) -> Result<Option<Job<'_>>> {
+        let watch = !matches!(wait, Wait::Never);
+
+        loop {
+            let (children, watch_handle) = self.client.get_children(&self.key, watch)?;
+            let claims = self.claims(&children);
+            let mut expiry = None;
+
+            for item in self.items(&children) {
+                if self.claimed(&claims, &item, &mut expiry)? {
+                    continue;
+                }
+                if let Some(job) = self.claim(&item)? {
+                    return Ok(Some(job));
+                }
+            }
+
+            // wake up when the earliest claim expires at the latest
+            let expiry = expiry.map(|expiry: u64| Instant::now() + Duration::from_millis(expiry - unix_millis().min(expiry)));
+            let limit = match (wait, expiry) {
+                (Wait::Never, _) => return Ok(None),
+                (Wait::Until(deadline), Some(expiry)) => Wait::Until(deadline.min(expiry)),
+                (Wait::Forever, Some(expiry)) => Wait::Until(expiry),
+                (wait, None) => wait,
+            };
+
+            if let Some(watch_handle) = watch_handle {
+                limit.on(watch_handle);
+            }
+            if let Wait::Until(deadline) = wait {
+                if Instant::now() >= deadline {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Returns the jobs ordered by their sequence numbers.
+    fn items(&self, children: &[String]) -> Vec<String> {
+        let name_start = self.key.len() + 1;
+
+        let mut items: Vec<(u64, &String)> = children.iter()
+            .filter(|child| child.len() > name_start && child[name_start..].starts_with(ITEM_PREFIX))
+            .filter_map(|child| sequence_number(child).map(|number| (number, child)))
+            .collect();
+        items.sort();
+
+        items.into_iter().map(|(_, item)| item.clone()).collect()
+    }
+
+    fn claims(&self, children: &[String]) -> BTreeSet<String> {
+        let claim_prefix = format!("{}/{}", self.key, CLAIM_PREFIX);
+        children.iter().filter(|child| child.starts_with(&claim_prefix)).cloned().collect()
+    }
+
+    fn claim_key(&self, item: &str) -> String {
+        format!("{}/{}{}", self.key, CLAIM_PREFIX, &item[self.key.len() + 1..])
+    }
+
+    /// Checks whether the job is claimed, erasing expired claims.
+    /// Tracks the earliest expiry of the claims seen.
+    fn claimed(&self, claims: &BTreeSet<String>, item: &str, earliest: &mut Option<u64>) -> Result<bool> {
+        let claim = self.claim_key(item);
+        if !claims.contains(&claim) {
+            return Ok(false);
+        }
+
+        let (version, expiry) = match self.client.get(&claim, false) {
+            Ok((version, expiry, _)) => (version, expiry.parse::<u64>().unwrap_or(0)),
+            Err(OffkvError::NoEntry) => return Ok(false),
+            Err(error) => return Err(error),
+        };
+
+        if expiry > unix_millis() {
+            *earliest = Some(earliest.map_or(expiry, |earliest| earliest.min(expiry)));
+            return Ok(true);
+        }
+
+        match self.client.erase(&claim, version) {
+            Ok(()) | Err(OffkvError::NoEntry) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Tries to claim the job, dead-lettering it if its attempts are exhausted.
+    ///
+    /// Returns `None` if the job was taken by someone else.
+    fn claim(&self, item: &str) -> Result<Option<Job<'_>>> {
+        let (version, encoded) = match self.client.get_bytes(item, false) {
+            Ok((version, encoded, _)) => (version, encoded),
+            Err(OffkvError::NoEntry) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let (attempts, value) = decode(&encoded);
+
+        if attempts >= self.options.max_attempts {
+            let letter = format!("{}/{}/{}", self.key, DEAD, &item[self.key.len() + 1..]);
+            return match self.client.commit(Transaction{
+                checks: vec![TxnCheck{key: item, version}],
+                ops: vec![
+                    TxnOp::CreateBytes{key: &letter, value, leased: false},
+                    TxnOp::Erase{key: item},
+                ],
+            }) {
+                Ok(_) | Err(OffkvError::TxnFailed(_)) => Ok(None),
+                Err(error) => Err(error),
+            };
+        }
+
+        let claim = self.claim_key(item);
+        let expiry = (unix_millis() + self.options.visibility_timeout.as_millis() as u64).to_string();
+        let encoded = encode(attempts + 1, value);
+
+        // fails if the job was changed or claimed concurrently
+        match self.client.commit(Transaction{
+            checks: vec![TxnCheck{key: item, version}],
+            ops: vec![
+                TxnOp::SetBytes{key: item, value: &encoded},
+                TxnOp::Create{key: &claim, value: &expiry, leased: true},
+            ],
+        }) {
+            Ok(results) => match results[..] {
+                [TxnOpResult::Set(_), TxnOpResult::Create(claim_version)] => Ok(Some(Job{
+                    _queue: self,
+                    claim: Lease::new(&self.client, claim, claim_version),
+                    item: String::from(item),
+                    value: value.to_vec(),
+                    attempts: attempts + 1,
+                })),
+                _ => unreachable!(),
+            },
+            Err(OffkvError::TxnFailed(_)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl Job<'_> {
+    /// Returns the key of the job.
+    pub fn key(&self) -> &str {
+        &self.item
+    }
+
+    /// Returns the value pushed with the job.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Returns the number of deliveries of the job including this one.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Removes the job from the queue.
+    ///
+    /// # Returns:
+    ///
+    /// * `OffkvError::LeaseLost` if the claim is gone, i.e. the job
+    ///   has been (or will be) delivered again
+    pub fn ack(self) -> Result<()> {
+        match self.claim.client.commit(Transaction{
+            checks: vec![TxnCheck{key: &self.claim.key, version: self.claim.version}],
+            ops: vec![
+                TxnOp::Erase{key: &self.item},
+                TxnOp::Erase{key: &self.claim.key},
+            ],
+        }) {
+            Ok(_) => Ok(()),
+            Err(OffkvError::TxnFailed(_)) | Err(OffkvError::NoEntry) => Err(OffkvError::LeaseLost),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Returns the job to the queue, same as dropping it.
+    pub fn release(self) {}
+}
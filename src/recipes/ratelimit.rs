@@ -0,0 +1,310 @@
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+
+use super::*;
+
+
+/// Rate limiter settings, see `RateLimiter::new`.
+#[derive(Clone, Debug)]
+pub struct RateLimiterOptions {
+    /// tokens added to the bucket per second (positive)
+    pub rate: f64,
+
+    /// capacity of the bucket, i.e. the largest burst (at least 1)
+    pub burst: f64,
+
+    /// number of tokens taken from the bucket at once; the surplus is spent locally
+    /// before going to the store again, trading precision for fewer round trips
+    pub prefetch: u32,
+
+    /// tokens per second (positive) the limiter grants locally while the store
+    /// is unreachable
+    pub fallback_rate: f64,
+}
+
+impl Default for RateLimiterOptions {
+    fn default() -> Self {
+        RateLimiterOptions{
+            rate: 10.,
+            burst: 10.,
+            prefetch: 1,
+            fallback_rate: 1.,
+        }
+    }
+}
+
+/// Token bucket shared by all the clients using the same key.
+///
+/// The bucket is stored as the number of tokens and the time of the last refill
+/// and is updated with `cas` (see `Client::update`), so the clients' clocks
+/// are assumed to be roughly in sync. While the connection is lost, the limiter
+/// falls back to a local bucket refilled at `fallback_rate`. If the clients keep
+/// racing for the bucket (see `ClientOptions::conflicts`), no token is taken and
+/// the limiter waits for the next one as if the bucket were empty.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::{RateLimiter,RateLimiterOptions};
+/// use std::time::{Duration,Instant};
+/// use std::thread;
+/// # let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let started = Instant::now();
+/// let workers: Vec<_> = (0..4).map(|_| thread::spawn(|| {
+///     let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///     let limiter = RateLimiter::new(&client, "/limits/geocoding", RateLimiterOptions{
+///         rate: 20.,
+///         burst: 10.,
+///         prefetch: 2,
+///         ..Default::default()
+///     }).unwrap();
+///
+///     for _ in 0..10 {
+///         limiter.acquire().unwrap();
+///     }
+/// })).collect();
+///
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+///
+/// // 40 tokens = the burst of 10 + 30 refilled at 20 per second,
+/// // up to the tokens prefetched but not spent
+/// assert!(started.elapsed() >= Duration::from_secs_f64((40. - 10. - 4. * 2.) / 20.));
+///
+/// # client.erase("/limits", 0);
+/// ```
+pub struct RateLimiter {
+    client: Client,
+    key: String,
+    options: RateLimiterOptions,
+    local: std::sync::Mutex<Local>,
+}
+
+struct Local {
+    // tokens taken from the bucket but not spent yet
+    prefetched: u32,
+    // local bucket used while the store is unreachable
+    fallback: f64,
+    refilled: Instant,
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Stores the number of tokens and the unix time (in milliseconds) of the last refill.
+fn encode_bucket(tokens: f64, refilled: u64) -> Vec<u8> {
+    format!("{} {}", tokens, refilled).into_bytes()
+}
+
+fn decode_bucket(bucket: &[u8]) -> Result<(f64, u64)> {
+    std::str::from_utf8(bucket).ok()
+        .and_then(|bucket| {
+            let mut parts = bucket.split(' ');
+            let bucket = (parts.next()?.parse::<f64>().ok()?, parts.next()?.parse::<u64>().ok()?);
+            match parts.next() {
+                None if bucket.0.is_finite() => Some(bucket),
+                _ => None,
+            }
+        })
+        .ok_or_else(|| OffkvError::Decode(String::from("the bucket is corrupt")))
+}
+
+/// Refills the bucket up to `now` and takes up to `prefetch` tokens.
+///
+/// # Returns:
+///
+/// * the tokens left, the number of tokens taken and the number of tokens
+///   missing for the next one
+fn take_tokens(options: &RateLimiterOptions, tokens: f64, refilled: u64, now: u64) -> (f64, u32, f64) {
+    let RateLimiterOptions{rate, burst, prefetch, ..} = *options;
+
+    let tokens = (tokens + now.saturating_sub(refilled) as f64 / 1000. * rate).min(burst);
+    let taken = tokens.floor().clamp(0., prefetch.max(1) as f64);
+    (tokens - taken, taken as u32, 1. - (tokens - taken).min(1.))
+}
+
+fn positive(value: f64, name: &str) -> Result<()> {
+    match value.is_finite() && value > 0. {
+        true => Ok(()),
+        false => Err(OffkvError::InvalidArgument(format!("{} must be positive, got {}", name, value))),
+    }
+}
+
+impl RateLimiter {
+    /// Creates a handle of the bucket stored under the key, creating the key's
+    /// ancestors if needed. A missing bucket is full.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the limiter is operated with
+    /// * `key` - key of the bucket
+    /// * `options` - limits, the same for all the clients
+    ///
+    /// # Returns:
+    ///
+    /// * the limiter or `OffkvError::InvalidArgument` if a rate isn't positive or the burst
+    ///   is less than 1
+    pub fn new(client: &Client, key: &str, options: RateLimiterOptions) -> Result<Self> {
+        positive(options.rate, "rate")?;
+        positive(options.fallback_rate, "fallback rate")?;
+        if !(options.burst >= 1. && options.burst.is_finite()) {
+            return Err(OffkvError::InvalidArgument(format!("burst must be at least 1, got {}", options.burst)));
+        }
+        ensure_path(client, &key[..key.rfind('/').unwrap_or(0)])?;
+
+        let fallback = options.fallback_rate.max(1.);
+        Ok(RateLimiter{
+            client: client.clone(),
+            key: String::from(key),
+            options,
+            local: std::sync::Mutex::new(Local{prefetched: 0, fallback, refilled: Instant::now()}),
+        })
+    }
+
+    /// Takes a token if one is available right now.
+    ///
+    /// # Returns:
+    ///
+    /// * whether the token was taken
+    /// * `OffkvError::Decode` if the bucket is corrupt
+    pub fn try_acquire(&self) -> Result<bool> {
+        self.take().map(|wait| wait.is_none())
+    }
+
+    /// Takes a token, blocking until one is available.
+    pub fn acquire(&self) -> Result<()> {
+        while let Some(wait) = self.take()? {
+            std::thread::sleep(wait);
+        }
+        Ok(())
+    }
+
+    /// Takes a token, blocking at most for the given time.
+    ///
+    /// # Returns:
+    ///
+    /// * `true` if the token was taken, `false` on timeout
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+
+        while let Some(wait) = self.take()? {
+            let now = Instant::now();
+            if now + wait > deadline {
+                return Ok(false);
+            }
+            std::thread::sleep(wait);
+        }
+        Ok(true)
+    }
+
+    /// Takes a token, returns the time until the next one if there are none.
+    fn take(&self) -> Result<Option<Duration>> {
+        let mut local = self.local.lock().unwrap();
+
+        if local.prefetched > 0 {
+            local.prefetched -= 1;
+            return Ok(None);
+        }
+
+        match self.take_shared() {
+            Ok((0, missing)) => Ok(Some(Duration::from_secs_f64(missing / self.options.rate))),
+            Ok((taken, _)) => {
+                local.prefetched = taken - 1;
+                Ok(None)
+            },
+            Err(OffkvError::ConnectionLost) | Err(OffkvError::Timeout) => Ok(self.take_fallback(&mut local)),
+            // other clients kept winning the race for the bucket
+            Err(OffkvError::TxnFailed(_)) => Ok(Some(Duration::from_secs_f64(1. / self.options.rate))),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Takes up to `prefetch` tokens from the bucket.
+    ///
+    /// Returns the number of tokens taken and the number of tokens missing
+    /// for the next one.
+    fn take_shared(&self) -> Result<(u32, f64)> {
+        let mut outcome = (0, 0.);
+
+        self.client.try_update(&self.key, |bucket| {
+            let now = unix_millis();
+            let (tokens, refilled) = match bucket {
+                Some(bucket) => decode_bucket(bucket)?,
+                None => (self.options.burst, now),
+            };
+
+            let (left, taken, missing) = take_tokens(&self.options, tokens, refilled, now);
+            outcome = (taken, missing);
+            Ok(Some(encode_bucket(left, now)))
+        })?;
+
+        Ok(outcome)
+    }
+
+    fn take_fallback(&self, local: &mut Local) -> Option<Duration> {
+        let rate = self.options.fallback_rate;
+        let now = Instant::now();
+
+        local.fallback = (local.fallback + (now - local.refilled).as_secs_f64() * rate).min(rate.max(1.));
+        local.refilled = now;
+
+        match local.fallback >= 1. {
+            true => {
+                local.fallback -= 1.;
+                None
+            },
+            false => Some(Duration::from_secs_f64((1. - local.fallback) / rate)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(rate: f64, burst: f64, prefetch: u32) -> RateLimiterOptions {
+        RateLimiterOptions{rate, burst, prefetch, ..Default::default()}
+    }
+
+    #[test]
+    fn buckets_round_trip() {
+        assert_eq!(decode_bucket(&encode_bucket(2.5, 1_600_000_000_000)).unwrap(), (2.5, 1_600_000_000_000));
+        assert_eq!(decode_bucket(&encode_bucket(0., 0)).unwrap(), (0., 0));
+    }
+
+    #[test]
+    fn corrupt_buckets_are_decode_errors() {
+        for bucket in [&b""[..], b"10", b"ten 1600000000000", b"10 -1", b"10 1600000000000 extra", b"NaN 0", b"\xff 0"].iter() {
+            match decode_bucket(bucket) {
+                Err(OffkvError::Decode(_)) => {},
+                other => panic!("{:?} must fail to decode, got {:?}", bucket, other),
+            }
+        }
+    }
+
+    #[test]
+    fn tokens_refill_up_to_the_burst() {
+        // 1.5 seconds at 2 tokens per second on top of 1 token
+        assert_eq!(take_tokens(&options(2., 10., 1), 1., 1_000, 2_500), (3., 1, 0.));
+        // a long pause fills the bucket only
+        assert_eq!(take_tokens(&options(2., 10., 1), 0., 0, 1_000_000), (9., 1, 0.));
+        // a clock going back refills nothing
+        assert_eq!(take_tokens(&options(2., 10., 1), 1.5, 2_000, 1_000), (0.5, 1, 0.5));
+    }
+
+    #[test]
+    fn prefetch_takes_whole_tokens_only() {
+        assert_eq!(take_tokens(&options(1., 10., 4), 2.5, 0, 0), (0.5, 2, 0.5));
+        assert_eq!(take_tokens(&options(1., 10., 4), 7., 0, 0), (3., 4, 0.));
+        // prefetch 0 still takes one
+        assert_eq!(take_tokens(&options(1., 10., 0), 7., 0, 0), (6., 1, 0.));
+    }
+
+    #[test]
+    fn empty_bucket_reports_the_missing_part() {
+        assert_eq!(take_tokens(&options(1., 10., 1), 0.25, 0, 0), (0.25, 0, 0.75));
+        assert_eq!(take_tokens(&options(1., 10., 1), 0., 0, 0), (0., 0, 1.));
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap,BTreeSet};
+use std::sync::{Arc,Weak,mpsc};
+use std::thread;
+
+use super::*;
+
+use crate::backoff::Backoff;
+
+
+/// Health state reported by a service instance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    Passing,
+    Warning,
+    Critical,
+}
+
+impl Health {
+    fn as_str(self) -> &'static str {
+        match self {
+            Health::Passing => "passing",
+            Health::Warning => "warning",
+            Health::Critical => "critical",
+        }
+    }
+
+    fn parse(health: &[u8]) -> Self {
+        match health {
+            b"passing" => Health::Passing,
+            b"warning" => Health::Warning,
+            _ => Health::Critical,
+        }
+    }
+}
+
+/// Registered instance of a service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceInstance {
+    /// id of the instance, unique within the service
+    pub id: String,
+
+    pub health: Health,
+
+    /// payload given on registration, e.g. the address of the instance
+    pub metadata: Vec<u8>,
+}
+
+/// Stores the health on the first line of the value, followed by the metadata.
+fn encode(health: Health, metadata: &[u8]) -> Vec<u8> {
+    let mut encoded = health.as_str().as_bytes().to_vec();
+    encoded.push(b'\n');
+    encoded.extend_from_slice(metadata);
+    encoded
+}
+
+fn decode(id: &str, encoded: &[u8]) -> ServiceInstance {
+    let split = encoded.iter().position(|byte| *byte == b'\n').unwrap_or(encoded.len());
+    ServiceInstance{
+        id: String::from(id),
+        health: Health::parse(&encoded[..split]),
+        metadata: encoded[(split + 1).min(encoded.len())..].to_vec(),
+    }
+}
+
+/// Service discovery: instances register as leased keys `<root>/<service>/<id>`,
+/// so an instance that crashes or loses its session deregisters automatically.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::{Health,ServiceRegistry};
+/// use std::{thread,time::Duration};
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let registry = ServiceRegistry::new(&client, "/services").unwrap();
+///
+/// let view = registry.watch("billing").unwrap();
+///
+/// let mut registration = registry.register("billing", "billing-1", "10.0.0.1:8080", Health::Passing).unwrap();
+/// let _another = registry.register("billing", "billing-2", "10.0.0.2:8080", Health::Passing).unwrap();
+/// thread::sleep(Duration::from_secs(1));
+/// assert_eq!(view.healthy().len(), 2);
+///
+/// registration.set_health(Health::Critical).unwrap();
+/// thread::sleep(Duration::from_secs(1));
+/// let healthy = view.healthy();
+/// assert_eq!(healthy.len(), 1);
+/// assert_eq!(healthy[0].metadata, b"10.0.0.2:8080".to_vec());
+///
+/// registration.deregister().unwrap();
+/// assert_eq!(registry.instances("billing").unwrap().len(), 1);
+///
+/// # drop(_another);
+/// # client.erase("/services", 0);
+/// ```
+pub struct ServiceRegistry {
+    client: Client,
+    root: String,
+}
+
+/// Registered instance, deregistered on drop.
+pub struct Registration<'a> {
+    registry: &'a ServiceRegistry,
+    key: String,
+    // version last written, 0 once the registration is gone
+    version: i64,
+    health: Health,
+    metadata: Vec<u8>,
+}
+
+/// Live view of the instances of a service, see `ServiceRegistry::watch`.
+///
+/// The view is updated by a background thread, which re-reads the service key
+/// and the instances as their watches fire, and is stopped on drop. liboffkv can't cancel
+/// a watch, so the watches pending then are dropped once their keys change.
+pub struct Membership {
+    shared: Arc<MembershipShared>,
+    signals: mpsc::Sender<Signal>,
+}
+
+struct MembershipShared {
+    instances: std::sync::Mutex<BTreeMap<String, ServiceInstance>>,
+    listeners: std::sync::Mutex<Vec<mpsc::Sender<Vec<ServiceInstance>>>>,
+}
+
+enum Signal {
+    // the children of the service key changed
+    Service,
+    // the instance with the key changed
+    Instance(String),
+    Stop,
+}
+
+/// State of the thread following a service.
+struct Follower {
+    client: Client,
+    service_key: String,
+    shared: Weak<MembershipShared>,
+    // the watches report here
+    signals: mpsc::Sender<Signal>,
+    // whether the children of the service key are watched
+    service_watched: bool,
+    // keys of the instances watched
+    watched: BTreeSet<String>,
+}
+
+impl ServiceRegistry {
+    /// Creates a registry of the services stored under the root key, creating
+    /// the key (and its ancestors) if needed.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the registry is operated with
+    /// * `root` - key the services are stored under
+    pub fn new(client: &Client, root: &str) -> Result<Self> {
+        ensure_path(client, root)?;
+        Ok(ServiceRegistry{client: client.clone(), root: String::from(root)})
+    }
+
+    fn service_key(&self, service: &str) -> String {
+        format!("{}/{}", self.root, service)
+    }
+
+    /// Registers an instance of the service.
+    ///
+    /// # Arguments:
+    ///
+    /// * `service` - name of the service
+    /// * `id` - id of the instance, unique within the service
+    /// * `metadata` - payload for the consumers, e.g. the address of the instance
+    /// * `health` - initial health state
+    ///
+    /// # Returns:
+    ///
+    /// * the registration or `OffkvError::EntryExists` if the id is taken
+    pub fn register<V: AsRef<[u8]>>(&self, service: &str, id: &str, metadata: V, health: Health)
+        -> Result<Registration<'_>> {
+
+        let service_key = self.service_key(service);
+        ensure_path(&self.client, &service_key)?;
+
+        let key = format!("{}/{}", service_key, id);
+        let metadata = metadata.as_ref().to_vec();
+        let version = self.client.create(&key, encode(health, &metadata), true)?;
+
+        Ok(Registration{registry: self, key, version, health, metadata})
+    }
+
+    /// Returns the instances of the service currently registered.
+    pub fn instances(&self, service: &str) -> Result<Vec<ServiceInstance>> {
+        let service_key = self.service_key(service);
+        let children = match self.client.get_children(&service_key, false) {
+            Ok((children, _)) => children,
+            Err(OffkvError::NoEntry) => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+
+        let mut instances = Vec::new();
+        for child in children {
+            match self.client.get_bytes(&child, false) {
+                Ok((_, value, _)) => instances.push(decode(&child[service_key.len() + 1..], &value)),
+                Err(OffkvError::NoEntry) => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(instances)
+    }
+
+    /// Starts following the instances of the service.
+    pub fn watch(&self, service: &str) -> Result<Membership> {
+        let service_key = self.service_key(service);
+        ensure_path(&self.client, &service_key)?;
+
+        let shared = Arc::new(MembershipShared{
+            instances: std::sync::Mutex::new(BTreeMap::new()),
+            listeners: std::sync::Mutex::new(Vec::new()),
+        });
+
+        let (signals, received) = mpsc::channel();
+        let follower = Follower{
+            client: self.client.clone(),
+            service_key,
+            shared: Arc::downgrade(&shared),
+            signals: signals.clone(),
+            service_watched: false,
+            watched: BTreeSet::new(),
+        };
+        thread::Builder::new()
+            .name(String::from("rsoffkv-registry"))
+            .spawn(move || follower.follow(received))
+            .expect("Failed to spawn registry watcher");
+
+        Ok(Membership{shared, signals})
+    }
+}
+
+impl Registration<'_> {
+    /// Updates the reported health state.
+    ///
+    /// # Returns:
+    ///
+    /// * `OffkvError::LeaseLost` if the instance is no longer registered,
+    ///   e.g. because the session expired
+    pub fn set_health(&mut self, health: Health) -> Result<()> {
+        self.write(health, &self.metadata.clone())?;
+        self.health = health;
+        Ok(())
+    }
+
+    /// Updates the metadata, see `set_health` for the errors.
+    pub fn set_metadata<V: AsRef<[u8]>>(&mut self, metadata: V) -> Result<()> {
+        let metadata = metadata.as_ref().to_vec();
+        self.write(self.health, &metadata)?;
+        self.metadata = metadata;
+        Ok(())
+    }
+
+    /// Removes the instance from the registry.
+    pub fn deregister(mut self) -> Result<()> {
+        let version = std::mem::replace(&mut self.version, 0);
+        match version {
+            0 => Ok(()),
+            version => match self.registry.client.erase(&self.key, version) {
+                Ok(()) | Err(OffkvError::NoEntry) => Ok(()),
+                Err(error) => Err(error),
+            },
+        }
+    }
+
+    /// Overwrites the registration only if it's the one this instance wrote last.
+    fn write(&mut self, health: Health, metadata: &[u8]) -> Result<()> {
+        if self.version == 0 {
+            return Err(OffkvError::LeaseLost);
+        }
+
+        self.version = match self.registry.client.cas(&self.key, encode(health, metadata), self.version) {
+            Ok(version) => version,
+            Err(OffkvError::NoEntry) => 0,
+            Err(error) => return Err(error),
+        };
+        match self.version {
+            0 => Err(OffkvError::LeaseLost),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if self.version != 0 {
+            let _ = self.registry.client.erase(&self.key, self.version);
+        }
+    }
+}
+
+impl Membership {
+    /// Returns all the instances.
+    pub fn instances(&self) -> Vec<ServiceInstance> {
+        self.shared.instances.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Returns the instances reporting `Health::Passing`.
+    pub fn healthy(&self) -> Vec<ServiceInstance> {
+        self.shared.instances.lock().unwrap().values()
+            .filter(|instance| instance.health == Health::Passing)
+            .cloned()
+            .collect()
+    }
+
+    /// Subscribes to changes; each change delivers all the instances.
+    pub fn subscribe(&self) -> mpsc::Receiver<Vec<ServiceInstance>> {
+        let (sender, receiver) = mpsc::channel();
+        self.shared.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        let _ = self.signals.send(Signal::Stop);
+    }
+}
+
+impl MembershipShared {
+    fn publish(&self, instances: &BTreeMap<String, ServiceInstance>) {
+        let snapshot: Vec<ServiceInstance> = instances.values().cloned().collect();
+        self.listeners.lock().unwrap().retain(|listener| listener.send(snapshot.clone()).is_ok());
+    }
+}
+
+impl Follower {
+    /// Re-reads what the watches report changed until the view is dropped.
+    fn follow(mut self, signals: mpsc::Receiver<Signal>) {
+        let mut failures = 0;
+        let mut signal = Ok(Signal::Service);
+
+        loop {
+            let shared = match self.shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+
+            let read = match signal {
+                Ok(Signal::Service) => {
+                    self.service_watched = false;
+                    self.list(&shared)
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => self.list(&shared),
+                Ok(Signal::Instance(key)) => {
+                    self.watched.remove(&key);
+                    self.read(&shared, key)
+                },
+                Ok(Signal::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            drop(shared);
+
+            signal = match read {
+                Ok(()) => {
+                    failures = 0;
+                    signals.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected)
+                },
+                // listing the service again reads the instances not watched
+                Err(_) => {
+                    failures += 1;
+                    signals.recv_timeout(Backoff::default().delay(failures - 1))
+                },
+            };
+        }
+    }
+
+    /// Lists the instances, reading the ones not watched yet.
+    fn list(&mut self, shared: &MembershipShared) -> Result<()> {
+        let (children, watch_handle) = self.client.get_children(&self.service_key, !self.service_watched)?;
+        if let Some(watch_handle) = watch_handle {
+            self.service_watched = true;
+            watch_handle.notify(self.signals.clone(), Signal::Service, Signal::Service);
+        }
+
+        {
+            let mut instances = shared.instances.lock().unwrap();
+            let before = instances.len();
+            instances.retain(|key, _| children.contains(key));
+            if instances.len() != before {
+                shared.publish(&instances);
+            }
+        }
+
+        for child in children {
+            if !self.watched.contains(&child) {
+                self.read(shared, child)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads an instance, watching it if it's registered.
+    fn read(&mut self, shared: &MembershipShared, key: String) -> Result<()> {
+        let instance = match self.client.get_bytes(&key, true) {
+            Ok((_, value, watch_handle)) => {
+                if let Some(watch_handle) = watch_handle {
+                    self.watched.insert(key.clone());
+                    watch_handle.notify(self.signals.clone(), Signal::Instance(key.clone()), Signal::Instance(key.clone()));
+                }
+                Some(decode(&key[self.service_key.len() + 1..], &value))
+            },
+            Err(OffkvError::NoEntry) => None,
+            Err(error) => return Err(error),
+        };
+
+        let mut instances = shared.instances.lock().unwrap();
+        let changed = match instance {
+            Some(instance) => instances.insert(key, instance.clone()) != Some(instance),
+            None => instances.remove(&key).is_some(),
+        };
+        if changed {
+            shared.publish(&instances);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instances_round_trip() {
+        for health in [Health::Passing, Health::Warning, Health::Critical].iter() {
+            for metadata in [&b""[..], b"10.0.0.1:8080", b"multi\nline\n", b"\xff\x00"].iter() {
+                let instance = decode("api-1", &encode(*health, metadata));
+                assert_eq!(instance, ServiceInstance{id: String::from("api-1"), health: *health, metadata: metadata.to_vec()});
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_health_is_critical() {
+        assert_eq!(decode("api-1", b"on fire\n10.0.0.1").health, Health::Critical);
+        assert_eq!(decode("api-1", b"").health, Health::Critical);
+    }
+
+    #[test]
+    fn values_without_metadata_decode() {
+        assert_eq!(decode("api-1", b"passing"), ServiceInstance{id: String::from("api-1"), health: Health::Passing, metadata: Vec::new()});
+    }
+}
@@ -0,0 +1,163 @@
+use std::time::{Duration,Instant};
+
+use super::*;
+
+
+/// Distributed read-write lock.
+///
+/// Readers and writers queue up in a single sequence of leased keys under
+/// the lock key, so the lock is fair: a reader waits for the last writer queued
+/// before it, a writer waits for its immediate predecessor. Locks are released
+/// when guards are dropped or the holders' sessions expire.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::RwLock;
+/// use std::time::Duration;
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let lock = RwLock::new(&client, "/locks/config").unwrap();
+///
+/// let another_client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let another_lock = RwLock::new(&another_client, "/locks/config").unwrap();
+///
+/// {
+///     let _reading = lock.read().unwrap();
+///
+///     // readers share the lock, writers have to wait
+///     assert!(another_lock.try_read().unwrap().is_some());
+///     assert!(another_lock.write_timeout(Duration::from_millis(100)).unwrap().is_none());
+/// }
+///
+/// let writing = lock.write().unwrap();
+/// writing.check().unwrap();
+/// assert!(another_lock.try_read().unwrap().is_none());
+///
+/// # drop(writing);
+/// # client.erase("/locks", 0);
+/// ```
+pub struct RwLock {
+    client: Client,
+    key: String,
+}
+
+/// Shared access to a `RwLock`, released on drop.
+pub struct RwLockReadGuard<'a> {
+    _lock: &'a RwLock,
+    lease: Lease,
+    token: i64,
+}
+
+/// Exclusive access to a `RwLock`, released on drop.
+pub struct RwLockWriteGuard<'a> {
+    _lock: &'a RwLock,
+    lease: Lease,
+    token: i64,
+}
+
+const READ_PREFIX: &str = "read-";
+const WRITE_PREFIX: &str = "write-";
+
+fn is_writer(key: &str) -> bool {
+    key[key.rfind('/').map_or(0, |position| position + 1)..].starts_with(WRITE_PREFIX)
+}
+
+impl RwLock {
+    /// Creates a handle of the lock stored under the key, creating the key
+    /// (and its ancestors) if needed.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the lock is operated with
+    /// * `key` - key of the lock
+    pub fn new(client: &Client, key: &str) -> Result<Self> {
+        ensure_path(client, key)?;
+        Ok(RwLock{client: client.clone(), key: String::from(key)})
+    }
+
+    /// Acquires shared access, blocking until no writer precedes.
+    pub fn read(&self) -> Result<RwLockReadGuard<'_>> {
+        self.acquire_read(Wait::Forever).map(Option::unwrap)
+    }
+
+    /// Acquires shared access if it's available right now.
+    ///
+    /// # Returns:
+    ///
+    /// * guard or `None` if a writer holds or waits for the lock
+    pub fn try_read(&self) -> Result<Option<RwLockReadGuard<'_>>> {
+        self.acquire_read(Wait::Never)
+    }
+
+    /// Acquires shared access, blocking at most for the given time.
+    ///
+    /// # Returns:
+    ///
+    /// * guard or `None` on timeout
+    pub fn read_timeout(&self, timeout: Duration) -> Result<Option<RwLockReadGuard<'_>>> {
+        self.acquire_read(Wait::Until(Instant::now() + timeout))
+    }
+
+    /// Acquires exclusive access, blocking until all preceding holders are gone.
+    pub fn write(&self) -> Result<RwLockWriteGuard<'_>> {
+        self.acquire_write(Wait::Forever).map(Option::unwrap)
+    }
+
+    /// Acquires exclusive access if the lock is free right now.
+    ///
+    /// # Returns:
+    ///
+    /// * guard or `None` if the lock is held
+    pub fn try_write(&self) -> Result<Option<RwLockWriteGuard<'_>>> {
+        self.acquire_write(Wait::Never)
+    }
+
+    /// Acquires exclusive access, blocking at most for the given time.
+    ///
+    /// # Returns:
+    ///
+    /// * guard or `None` on timeout
+    pub fn write_timeout(&self, timeout: Duration) -> Result<Option<RwLockWriteGuard<'_>>> {
+        self.acquire_write(Wait::Until(Instant::now() + timeout))
+    }
+
+    fn acquire_read(&self, wait: Wait) -> Result<Option<RwLockReadGuard<'_>>> {
+        // wait for the last writer queued before
+        let acquired = queue_up(&self.client, &self.key, READ_PREFIX, b"", wait, |queue, position|
+            Ok(queue[..position].iter().rev().find(|queued| is_writer(queued)).cloned()))?;
+
+        Ok(acquired.map(|(lease, token)| RwLockReadGuard{_lock: self, lease, token}))
+    }
+
+    fn acquire_write(&self, wait: Wait) -> Result<Option<RwLockWriteGuard<'_>>> {
+        // wait for the immediate predecessor
+        let acquired = queue_up(&self.client, &self.key, WRITE_PREFIX, b"", wait, |queue, position|
+            Ok(position.checked_sub(1).map(|predecessor| queue[predecessor].clone())))?;
+
+        Ok(acquired.map(|(lease, token)| RwLockWriteGuard{_lock: self, lease, token}))
+    }
+}
+
+impl RwLockReadGuard<'_> {
+    /// Returns the fencing token, see `MutexGuard::token`.
+    pub fn token(&self) -> i64 {
+        self.token
+    }
+
+    /// Checks that the lock is still held, see `MutexGuard::check`.
+    pub fn check(&self) -> Result<()> {
+        self.lease.check()
+    }
+}
+
+impl RwLockWriteGuard<'_> {
+    /// Returns the fencing token, see `MutexGuard::token`.
+    pub fn token(&self) -> i64 {
+        self.token
+    }
+
+    /// Checks that the lock is still held, see `MutexGuard::check`.
+    pub fn check(&self) -> Result<()> {
+        self.lease.check()
+    }
+}
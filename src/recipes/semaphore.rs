@@ -0,0 +1,188 @@
+use std::time::{Duration,Instant};
+
+use super::*;
+
+
+/// Distributed counting semaphore.
+///
+/// The number of permits is stored under the semaphore key, so all handles
+/// share it and it may be changed at runtime. Contenders queue up as leased
+/// sequential keys, and the first `limit` of them hold permits; a permit
+/// of a crashed holder is reclaimed once its session expires. A holder marks
+/// its key as held. Each waiting contender watches the key of the one queued
+/// right before it, which wakes it up by leaving or getting a permit; only the first
+/// waiter watches the children of the semaphore key, so it notices both
+/// released permits and raised limits.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::Semaphore;
+/// use std::time::Duration;
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let semaphore = Semaphore::new(&client, "/semaphores/reindex", 2).unwrap();
+///
+/// let first = semaphore.acquire().unwrap();
+/// let _second = semaphore.acquire().unwrap();
+/// assert!(semaphore.try_acquire().unwrap().is_none());
+///
+/// // a released permit is available again
+/// drop(first);
+/// let _third = semaphore.acquire_timeout(Duration::from_secs(1)).unwrap().unwrap();
+///
+/// // as are the added ones
+/// semaphore.set_limit(3).unwrap();
+/// assert_eq!(semaphore.limit().unwrap(), 3);
+/// assert!(semaphore.try_acquire().unwrap().is_some());
+///
+/// # client.erase("/semaphores", 0);
+/// ```
+pub struct Semaphore {
+    client: Client,
+    key: String,
+}
+
+/// Permit of a `Semaphore`, released on drop.
+pub struct SemaphorePermit<'a> {
+    _semaphore: &'a Semaphore,
+    lease: Lease,
+}
+
+const PERMIT_PREFIX: &str = "permit-";
+const LIMIT: &str = "limit";
+const TOUCH: &str = "touch";
+const HELD: &str = "held";
+
+impl Semaphore {
+    /// Creates a handle of the semaphore stored under the key, creating the key
+    /// (and its ancestors) if needed.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the semaphore is operated with
+    /// * `key` - key of the semaphore
+    /// * `permits` - number of permits, used only if the semaphore doesn't exist yet
+    pub fn new(client: &Client, key: &str, permits: u32) -> Result<Self> {
+        ensure_path(client, key)?;
+
+        match client.create(&format!("{}/{}", key, LIMIT), permits.to_string(), false) {
+            Ok(_) | Err(OffkvError::EntryExists) => {},
+            Err(error) => return Err(error),
+        }
+        Ok(Semaphore{client: client.clone(), key: String::from(key)})
+    }
+
+    /// Returns the current number of permits.
+    ///
+    /// # Returns:
+    ///
+    /// * the number of permits
+    /// * `OffkvError::Decode` if the stored limit is not a number
+    pub fn limit(&self) -> Result<u32> {
+        let (_, limit, _) = self.client.get(&format!("{}/{}", self.key, LIMIT), false)?;
+        limit.parse().map_err(|_| OffkvError::Decode(String::from("the semaphore limit is not a number")))
+    }
+
+    /// Changes the number of permits. Lowering the limit doesn't revoke
+    /// the permits held, the new ones are just not granted until the number
+    /// of holders drops below the limit.
+    pub fn set_limit(&self, permits: u32) -> Result<()> {
+        self.client.set(&format!("{}/{}", self.key, LIMIT), permits.to_string())?;
+
+        // wake the waiters up
+        let touch = format!("{}/{}", self.key, TOUCH);
+        match self.client.create(&touch, "", false) {
+            Ok(_) => self.client.erase(&touch, 0).or_else(|error| match error {
+                OffkvError::NoEntry => Ok(()),
+                error => Err(error),
+            }),
+            // someone else is waking them up
+            Err(OffkvError::EntryExists) => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Acquires a permit, blocking until one is available.
+    pub fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        self.acquire_within(Wait::Forever).map(Option::unwrap)
+    }
+
+    /// Acquires a permit if one is available right now.
+    ///
+    /// # Returns:
+    ///
+    /// * the permit or `None` if all of them are held
+    pub fn try_acquire(&self) -> Result<Option<SemaphorePermit<'_>>> {
+        self.acquire_within(Wait::Never)
+    }
+
+    /// Acquires a permit, blocking at most for the given time.
+    ///
+    /// # Returns:
+    ///
+    /// * the permit or `None` on timeout
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<Option<SemaphorePermit<'_>>> {
+        self.acquire_within(Wait::Until(Instant::now() + timeout))
+    }
+
+    fn acquire_within(&self, wait: Wait) -> Result<Option<SemaphorePermit<'_>>> {
+        let (key, version, _) = create_sequential(&self.client, &self.key, PERMIT_PREFIX, b"", true)?;
+        let mut lease = Lease::new(&self.client, key, version);
+        // set by the first waiter before reading the queue
+        let mut queue_watch = None;
+
+        loop {
+            let queue = sequential_children(&self.client, &self.key, PERMIT_PREFIX)?;
+            let position = match queue.iter().position(|queued| *queued == lease.key) {
+                Some(position) => position,
+                None => return Err(OffkvError::LeaseLost),
+            };
+
+            if position < self.limit()? as usize {
+                // wakes the next waiter up, it's the first one now
+                lease.version = match self.client.cas(&lease.key, HELD, lease.version)? {
+                    0 => return Err(OffkvError::LeaseLost),
+                    version => version,
+                };
+                return Ok(Some(SemaphorePermit{_semaphore: self, lease}));
+            }
+            if let Wait::Never = wait {
+                return Ok(None);
+            }
+
+            let watch_handle = match (queue_watch.take(), position.checked_sub(1)) {
+                (Some(watch_handle), _) => watch_handle,
+                (None, Some(previous)) => match self.client.get_bytes(&queue[previous], true) {
+                    Ok((_, value, Some(watch_handle))) if value != HELD.as_bytes() => watch_handle,
+                    Err(OffkvError::NoEntry) => continue,
+                    // the previous one holds a permit, making this one the first waiter
+                    Ok(_) => {
+                        queue_watch = self.client.get_children(&self.key, true)?.1;
+                        continue;
+                    },
+                    Err(error) => return Err(error),
+                },
+                (None, None) => {
+                    queue_watch = self.client.get_children(&self.key, true)?.1;
+                    continue;
+                },
+            };
+
+            if !wait.on(watch_handle) {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl SemaphorePermit<'_> {
+    /// Returns the leased key representing the permit.
+    pub fn key(&self) -> &str {
+        self.lease.key()
+    }
+
+    /// Checks that the permit is still held, see `MutexGuard::check`.
+    pub fn check(&self) -> Result<()> {
+        self.lease.check()
+    }
+}
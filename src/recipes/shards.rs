@@ -0,0 +1,406 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc,Weak,mpsc};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use super::*;
+
+use crate::backoff::Backoff;
+use crate::client::SessionEvent;
+use crate::txn::{Transaction,TxnCheck,TxnOp};
+
+
+/// Distribution of shards among workers.
+///
+/// Workers register as leased keys under `<key>/members`, registering again
+/// once the client reconnects after the session expired (and the expired session's
+/// key is gone). The leader (see
+/// `LeaderElection`) assigns each shard to a member with rendezvous hashing,
+/// so when a member joins or leaves only the shards it gains or loses move.
+/// The assignment of all the members is written under `<key>/assignment` in one
+/// `commit`, checked against the leader's candidacy so that a deposed leader can't
+/// overwrite its successor's assignment, and each worker watches its own part.
+///
+/// All the workers must be given the same shards.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::recipes::ShardAssigner;
+/// use std::collections::BTreeSet;
+/// use std::{thread,time::Duration};
+/// # let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let shards: Vec<String> = (0..12).map(|partition| format!("orders-{}", partition)).collect();
+/// let workers: Vec<ShardAssigner> = (0..3).map(|worker| {
+///     let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///     ShardAssigner::new(&client, "/shards/orders", &format!("worker-{}", worker), &shards).unwrap()
+/// }).collect();
+///
+/// // waits until each worker owns some of the shards, and all of them once
+/// let settled = |workers: &[ShardAssigner]| {
+///     for _ in 0..50 {
+///         let owned: Vec<Vec<String>> = workers.iter().map(|worker| worker.shards()).collect();
+///         let all: BTreeSet<&String> = owned.iter().flatten().collect();
+///         if owned.iter().all(|shards| !shards.is_empty()) && owned.iter().map(Vec::len).sum::<usize>() == 12 && all.len() == 12 {
+///             return;
+///         }
+///         thread::sleep(Duration::from_millis(100));
+///     }
+///     panic!("shards are not distributed");
+/// };
+/// settled(&workers);
+///
+/// // a worker leaves, the others keep their shards and take over its ones
+/// let kept: Vec<Vec<String>> = workers[1..].iter().map(|worker| worker.shards()).collect();
+/// let mut workers = workers;
+/// workers.remove(0);
+/// settled(&workers);
+///
+/// for (worker, kept) in workers.iter().zip(kept) {
+///     let shards = worker.shards();
+///     assert!(kept.iter().all(|shard| shards.contains(shard)));
+/// }
+///
+/// # drop(workers);
+/// # client.erase("/shards", 0);
+/// ```
+pub struct ShardAssigner {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    client: Client,
+    key: String,
+    id: String,
+    shards: Vec<String>,
+    election: LeaderElection,
+    // the member key, `None` while the session it was created in is gone
+    member: std::sync::Mutex<Option<Lease>>,
+    current: std::sync::Mutex<Vec<String>>,
+    listeners: std::sync::Mutex<Vec<mpsc::Sender<Vec<String>>>>,
+    stopped: AtomicBool,
+}
+
+/// 64-bit FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Assigns each shard to the member with the highest weight for it.
+fn rendezvous<'a>(shards: &'a [String], members: &[String]) -> BTreeMap<String, Vec<&'a str>> {
+    let mut assignment: BTreeMap<String, Vec<&str>> = members.iter().map(|member| (member.clone(), Vec::new())).collect();
+
+    for shard in shards {
+        let owner = members.iter().max_by_key(|member| {
+            fnv1a(format!("{}\0{}", shard, member).as_bytes())
+        });
+        if let Some(owner) = owner {
+            assignment.get_mut(owner).unwrap().push(shard);
+        }
+    }
+    assignment
+}
+
+fn parse_shards(value: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(value).lines().map(String::from).collect()
+}
+
+impl ShardAssigner {
+    /// Registers the worker and starts following its shards.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the worker is registered with
+    /// * `key` - key of the group of workers
+    /// * `id` - id of the worker, unique within the group
+    /// * `shards` - all the shards to distribute
+    ///
+    /// # Returns:
+    ///
+    /// * the assigner or `OffkvError::EntryExists` if the id is taken
+    pub fn new(client: &Client, key: &str, id: &str, shards: &[String]) -> Result<Self> {
+        ensure_path(client, &format!("{}/members", key))?;
+        ensure_path(client, &format!("{}/assignment", key))?;
+        let session_events = client.session_events();
+        let member_key = format!("{}/members/{}", key, id);
+        let member = Lease::new(client, member_key.clone(), client.create(&member_key, "", true)?);
+
+        let election = LeaderElection::new(client, &format!("{}/leader", key), id)?;
+        let events = election.events();
+        election.campaign()?;
+
+        let shared = Arc::new(Shared{
+            client: client.clone(),
+            key: String::from(key),
+            id: String::from(id),
+            shards: shards.to_vec(),
+            election,
+            member: std::sync::Mutex::new(Some(member)),
+            current: std::sync::Mutex::new(Vec::new()),
+            listeners: std::sync::Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+        });
+
+        let (client, assigner) = (client.clone(), Arc::downgrade(&shared));
+        thread::Builder::new()
+            .name(String::from("rsoffkv-shards-leader"))
+            .spawn(move || Shared::assign(client, assigner, events))
+            .expect("Failed to spawn shard assigner");
+
+        let (client, worker) = (shared.client.clone(), Arc::downgrade(&shared));
+        thread::Builder::new()
+            .name(String::from("rsoffkv-shards-worker"))
+            .spawn(move || Shared::follow(client, worker))
+            .expect("Failed to spawn shard follower");
+
+        let watcher = Arc::downgrade(&shared);
+        thread::Builder::new()
+            .name(String::from("rsoffkv-shards-session"))
+            .spawn(move || Shared::follow_session(watcher, session_events))
+            .expect("Failed to spawn shard session watcher");
+
+        Ok(ShardAssigner{shared})
+    }
+
+    /// Returns the shards currently assigned to the worker.
+    pub fn shards(&self) -> Vec<String> {
+        self.shared.current.lock().unwrap().clone()
+    }
+
+    /// Subscribes to changes of the worker's shards; the current ones are delivered first.
+    pub fn subscribe(&self) -> mpsc::Receiver<Vec<String>> {
+        let (sender, receiver) = mpsc::channel();
+
+        let current = self.shared.current.lock().unwrap();
+        let _ = sender.send(current.clone());
+        self.shared.listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    /// Returns whether the worker assigns the shards at the moment.
+    pub fn is_leader(&self) -> bool {
+        self.shared.election.is_leader()
+    }
+}
+
+impl Drop for ShardAssigner {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+
+        // wakes up both the leader (if it's us) and our follower
+        self.shared.member.lock().unwrap().take();
+        let _ = self.shared.client.erase(&format!("{}/assignment/{}", self.shared.key, self.shared.id), 0);
+    }
+}
+
+impl Shared {
+    /// Writes the assignment for the current members while leading.
+    fn assign(client: Client, shared: Weak<Shared>, events: mpsc::Receiver<LeaderEvent>) {
+        let mut failures = 0;
+
+        loop {
+            let shared = match shared.upgrade() {
+                Some(shared) if !shared.stopped.load(Ordering::SeqCst) => shared,
+                _ => return,
+            };
+
+            if !shared.election.is_leader() {
+                drop(shared);
+                if let Err(mpsc::RecvTimeoutError::Disconnected) = events.recv_timeout(Duration::from_secs(1)) {
+                    return;
+                }
+                continue;
+            }
+
+            match shared.write_assignment(&client) {
+                Ok(watch_handle) => {
+                    failures = 0;
+                    drop(shared);
+                    if let Some(watch_handle) = watch_handle {
+                        watch_handle.wait();
+                    }
+                },
+                Err(_) => {
+                    drop(shared);
+                    thread::sleep(Backoff::default().delay(failures));
+                    failures += 1;
+                },
+            }
+        }
+    }
+
+    /// Returns a watch for the members, `None` if no longer leading.
+    fn write_assignment<'a>(&self, client: &'a Client) -> Result<Option<WatchHandle<'a>>> {
+        let (candidacy, candidacy_version) = match self.election.candidacy() {
+            Some(candidacy) => candidacy,
+            None => return Ok(None),
+        };
+
+        let members_key = format!("{}/members", self.key);
+        let assignment_key = format!("{}/assignment", self.key);
+
+        let (members, watch_handle) = client.get_children(&members_key, true)?;
+        let members: Vec<String> = members.iter().map(|member| member[members_key.len() + 1..].to_string()).collect();
+        let (assigned, _) = client.get_children(&assignment_key, false)?;
+
+        let values: Vec<(String, String)> = rendezvous(&self.shards, &members).into_iter()
+            .map(|(member, shards)| (format!("{}/{}", assignment_key, member), shards.join("\n")))
+            .collect();
+
+        let mut ops: Vec<TxnOp> = values.iter()
+            .map(|(key, shards)| match assigned.contains(key) {
+                true => TxnOp::Set{key, value: shards},
+                false => TxnOp::Create{key, value: shards, leased: false},
+            })
+            .collect();
+        ops.extend(assigned.iter()
+            .filter(|key| !values.iter().any(|(assigned, _)| assigned == *key))
+            .map(|key| TxnOp::Erase{key}));
+
+        client.commit(Transaction{checks: vec![TxnCheck{key: &candidacy, version: candidacy_version}], ops})?;
+        Ok(watch_handle)
+    }
+
+    /// Watches the worker's assignment.
+    fn follow(client: Client, shared: Weak<Shared>) {
+        let mut failures = 0;
+
+        loop {
+            let shared = match shared.upgrade() {
+                Some(shared) if !shared.stopped.load(Ordering::SeqCst) => shared,
+                _ => return,
+            };
+
+            let key = format!("{}/assignment/{}", shared.key, shared.id);
+            let watched = match client.get_bytes(&key, true) {
+                Ok((_, value, watch_handle)) => Ok((parse_shards(&value), watch_handle)),
+                Err(OffkvError::NoEntry) => match client.exists(&key, true) {
+                    Ok((0, watch_handle)) => Ok((Vec::new(), watch_handle)),
+                    // assigned in between, read it
+                    Ok(_) => continue,
+                    Err(error) => Err(error),
+                },
+                Err(error) => Err(error),
+            };
+
+            match watched {
+                Ok((shards, watch_handle)) => {
+                    failures = 0;
+                    shared.publish(shards);
+                    drop(shared);
+                    if let Some(watch_handle) = watch_handle {
+                        watch_handle.wait();
+                    }
+                },
+                Err(_) => {
+                    drop(shared);
+                    thread::sleep(Backoff::default().delay(failures));
+                    failures += 1;
+                },
+            }
+        }
+    }
+
+    /// Creates the member key again if it's gone with its session.
+    fn register(&self) -> Result<()> {
+        let mut member = self.member.lock().unwrap();
+        if member.is_none() {
+            let key = format!("{}/members/{}", self.key, self.id);
+            let version = self.client.create(&key, "", true)?;
+            *member = Some(Lease::new(&self.client, key, version));
+        }
+        Ok(())
+    }
+
+    /// Drops the member key on session expiration and registers again after reconnection.
+    fn follow_session(shared: Weak<Shared>, events: mpsc::Receiver<SessionEvent>) {
+        let mut registering = false;
+
+        loop {
+            let event = events.recv_timeout(Duration::from_secs(1));
+
+            let shared = match shared.upgrade() {
+                Some(shared) if !shared.stopped.load(Ordering::SeqCst) => shared,
+                _ => return,
+            };
+
+            match event {
+                Ok(SessionEvent::Expired) => if let Some(lease) = shared.member.lock().unwrap().take() {
+                    lease.disarm();
+                },
+                Ok(SessionEvent::Reconnected) => registering = true,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                _ => {},
+            }
+
+            // the key may be held by the expired session for a while, retried every second
+            if registering {
+                registering = shared.register().is_err();
+            }
+        }
+    }
+
+    fn publish(&self, shards: Vec<String>) {
+        let mut current = self.current.lock().unwrap();
+        if *current == shards {
+            return;
+        }
+
+        *current = shards;
+        self.listeners.lock().unwrap().retain(|listener| listener.send(current.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shards(count: usize) -> Vec<String> {
+        (0..count).map(|shard| format!("shard-{}", shard)).collect()
+    }
+
+    fn members(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    #[test]
+    fn every_shard_is_assigned_once() {
+        let shards = shards(100);
+        let assignment = rendezvous(&shards, &members(&["a", "b", "c"]));
+
+        assert_eq!(assignment.len(), 3);
+        let mut assigned: Vec<&str> = assignment.values().flatten().cloned().collect();
+        assigned.sort_unstable();
+        let mut expected: Vec<&str> = shards.iter().map(String::as_str).collect();
+        expected.sort_unstable();
+        assert_eq!(assigned, expected);
+        assert!(assignment.values().all(|owned| !owned.is_empty()));
+    }
+
+    #[test]
+    fn assignment_does_not_depend_on_member_order() {
+        let shards = shards(50);
+        assert_eq!(rendezvous(&shards, &members(&["a", "b", "c"])), rendezvous(&shards, &members(&["c", "a", "b"])));
+    }
+
+    #[test]
+    fn only_shards_of_the_leaving_member_move() {
+        let shards = shards(100);
+        let before = rendezvous(&shards, &members(&["a", "b", "c"]));
+        let after = rendezvous(&shards, &members(&["a", "c"]));
+
+        for member in ["a", "c"].iter() {
+            assert!(before[*member].iter().all(|shard| after[*member].contains(shard)));
+        }
+    }
+
+    #[test]
+    fn members_without_shards_get_an_empty_assignment() {
+        let assignment = rendezvous(&[], &members(&["a"]));
+        assert_eq!(assignment["a"], Vec::<&str>::new());
+        assert!(rendezvous(&shards(3), &[]).is_empty());
+    }
+}
@@ -50,7 +50,8 @@ pub enum OffkvError {
     ConnectionLost,
 
     /// returned from commit if the transaction was failed (not all checks are satisfied
-    /// or any operation failed)
+    /// or any operation failed); also returned from optimistic helpers
+    /// (e.g. `Client::update`) if concurrent writers kept winning
     ///
     /// contains an index of failed operation
     TxnFailed(u32),
@@ -60,6 +61,41 @@ pub enum OffkvError {
 
     /// can be returned from any function
     OutOfMemory,
+
+    /// returned from any function if the operation wasn't completed
+    /// before its deadline (see `Client::with_deadline`)
+    Timeout,
+
+    /// returned from `create`, `cas` and `commit` retried after a transient failure
+    /// (see `ClientOptions::retry`) if the retry failed in a way the failed attempt
+    /// could have caused and re-reading the keys didn't show the written values:
+    /// the operation may or may not have been applied
+    OutcomeUnknown,
+
+    /// returned if a value read from the store can't be decoded into the requested type,
+    /// e.g. by `Client::get` if the value is not valid UTF-8
+    ///
+    /// contains the description of the problem
+    Decode(String),
+
+    /// returned from recipes (see `rsoffkv::recipes`) if a leased key they rely on
+    /// is gone, e.g. because the session expired
+    LeaseLost,
+
+    /// returned if a value can't be encoded by a codec (see `rsoffkv::codec`)
+    ///
+    /// contains the description of the problem
+    Encode(String),
+
+    /// returned from `recipes::Counter` and `recipes::IdAllocator` if the counter
+    /// would go out of range
+    Overflow,
+
+    /// returned if an argument is out of its domain, e.g. a non-positive rate
+    /// given to `recipes::RateLimiter::new`
+    ///
+    /// contains the description of the problem
+    InvalidArgument(String),
 }
 
 
@@ -78,8 +114,8 @@ pub(crate) fn from_error_code(error_code: i64) -> Option<OffkvError> {
     }
 }
 
-fn to_error_code(error: &OffkvError) -> c_int {
-    (match *error {
+fn to_error_code(error: &OffkvError) -> Option<c_int> {
+    Some(match *error {
         OffkvError::InvalidAddress => OffkvErrorCode::OFFKV_EADDR,
         OffkvError::InvalidKey => OffkvErrorCode::OFFKV_EKEY,
         OffkvError::NoEntry => OffkvErrorCode::OFFKV_ENOENT,
@@ -89,19 +125,36 @@ fn to_error_code(error: &OffkvError) -> c_int {
         OffkvError::TxnFailed(_) => OffkvErrorCode::OFFKV_ETXN,
         OffkvError::ServiceError => OffkvErrorCode::OFFKV_ESRV,
         OffkvError::OutOfMemory => OffkvErrorCode::OFFKV_ENOMEM,
-    }) as c_int
+        // errors originating in rsoffkv itself
+        OffkvError::Timeout | OffkvError::OutcomeUnknown | OffkvError::Decode(_) | OffkvError::LeaseLost | OffkvError::Encode(_)
+            | OffkvError::Overflow | OffkvError::InvalidArgument(_) => return None,
+    } as c_int)
 }
 
 
 impl fmt::Display for OffkvError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let descr = unsafe {
-            CString::from_raw(offkv_error_descr(to_error_code(&*self)) as *mut c_char)
-        }.into_string().unwrap();
-
-        match &*self {
+        let descr = match to_error_code(self) {
+            Some(error_code) => unsafe {
+                CString::from_raw(offkv_error_descr(error_code) as *mut c_char)
+            }.into_string().unwrap(),
+            None => String::from(match *self {
+                OffkvError::Timeout => "Operation timed out",
+                OffkvError::OutcomeUnknown => "Outcome of the retried operation is unknown",
+                OffkvError::Decode(_) => "Failed to decode the value",
+                OffkvError::LeaseLost => "Leased key is lost",
+                OffkvError::Encode(_) => "Failed to encode the value",
+                OffkvError::Overflow => "Counter overflow",
+                OffkvError::InvalidArgument(_) => "Invalid argument",
+                _ => unreachable!(),
+            }),
+        };
+
+        match self {
             OffkvError::TxnFailed(index)
                 => write!(f, "{} (failed operation index: {})", descr, index),
+            OffkvError::Decode(problem) | OffkvError::Encode(problem) | OffkvError::InvalidArgument(problem)
+                => write!(f, "{}: {}", descr, problem),
             _ => write!(f, "{}", descr),
         }
     }
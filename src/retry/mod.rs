@@ -0,0 +1,120 @@
+use std::thread;
+use std::time::{Duration,Instant};
+
+use crate::backoff::Backoff;
+use crate::result::OffkvError;
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// Policy for retrying operations failed with transient errors
+/// (`ConnectionLost` and `ServiceError`).
+///
+/// A policy can be installed into a client (see `ClientOptions::retry`) or
+/// used directly to retry arbitrary code with `RetryPolicy::run`.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// delays between attempts
+    pub backoff: Backoff,
+
+    /// maximum number of attempts including the first one
+    pub max_attempts: u32,
+
+    /// if set, no attempt is started once this much time elapsed since the first one
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy{
+            backoff: Backoff::default(),
+            max_attempts: 5,
+            deadline: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns `true` if an operation failed with the error may succeed if retried.
+    pub fn is_transient(error: &OffkvError) -> bool {
+        matches!(error, OffkvError::ConnectionLost | OffkvError::ServiceError)
+    }
+
+    /// Runs `attempt` until it succeeds, fails with a non-transient error
+    /// or the policy is exhausted; in the latter case the last error is returned.
+    ///
+    /// # Arguments:
+    ///
+    /// * `attempt` - function to run, it's given the number of the attempt (starting with 0)
+    ///
+    /// # Example:
+    /// ```
+    /// # use rsoffkv::client::Client;
+    /// use rsoffkv::retry::RetryPolicy;
+    /// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+    /// client.set("/key", "value").unwrap();
+    ///
+    /// let (_, value, _) = RetryPolicy::default()
+    ///     .run(|_attempt| client.get("/key", false))
+    ///     .unwrap();
+    /// assert_eq!(value, String::from("value"));
+    ///
+    /// # client.erase("/key", 0);
+    /// ```
+    pub fn run<T, F>(&self, attempt: F) -> Result<T>
+        where F: FnMut(u32) -> Result<T> {
+
+        self.run_on(RetryPolicy::is_transient, attempt)
+    }
+
+    /// Does `run`, retrying only the errors `transient` returns `true` for.
+    pub(crate) fn run_on<T, F, P>(&self, transient: P, mut attempt: F) -> Result<T>
+        where F: FnMut(u32) -> Result<T>, P: Fn(&OffkvError) -> bool {
+
+        let start = Instant::now();
+
+        for n in 0.. {
+            let result = attempt(n);
+            let delay = self.backoff.delay(n);
+
+            let retry = match result {
+                Err(ref error) => transient(error)
+                    && n + 1 < self.max_attempts
+                    && self.deadline.is_none_or(|deadline| start.elapsed() + delay < deadline),
+                Ok(_) => false,
+            };
+
+            if !retry {
+                return result;
+            }
+            thread::sleep(delay);
+        }
+
+        unreachable!()
+    }
+
+    /// Runs an optimistic `attempt` until it's applied (returns `Some`) or fails;
+    /// an attempt returning `None` lost a race and is started over after a delay.
+    ///
+    /// Returns `OffkvError::TxnFailed` if the policy is exhausted.
+    pub(crate) fn run_optimistic<T, F>(&self, mut attempt: F) -> Result<T>
+        where F: FnMut(u32) -> Result<Option<T>> {
+
+        let start = Instant::now();
+
+        for n in 0..self.max_attempts.max(1) {
+            if let Some(result) = attempt(n)? {
+                return Ok(result);
+            }
+
+            let delay = self.backoff.delay(n);
+            if self.deadline.is_some_and(|deadline| start.elapsed() + delay >= deadline) {
+                break;
+            }
+            thread::sleep(delay);
+        }
+
+        Err(OffkvError::TxnFailed(0))
+    }
+}
@@ -0,0 +1,401 @@
+//! Periodic jobs run once per tick across all replicas, requires the `scheduler` feature.
+//!
+//! Every replica schedules the same jobs; a replica takes a job's tick only
+//! while holding the job's lock (see `recipes::Mutex`) and only if it manages to move
+//! the job's last-run key past the tick with `cas`. A tick is claimed before the job
+//! runs, so a job runs at most once per tick even if its replica crashes midway.
+//! Ticks missed while no replica was running (e.g. during a failover) are picked up
+//! on the next check, see `SchedulerOptions::catch_up`.
+//!
+//! Keys used per job: `<prefix>/<job>/lock`, `<prefix>/<job>/last_run` (unix time
+//! of the last tick claimed) and `<prefix>/<job>/history`.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime,TimeZone,Utc};
+pub use cron::Schedule;
+
+use crate::client::Client;
+use crate::recipes::{self,Mutex};
+use crate::result::OffkvError;
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
+/// Scheduler settings, see `Scheduler::with_options`.
+#[derive(Clone, Debug)]
+pub struct SchedulerOptions {
+    /// how many of the due ticks of a job are run on a check, the latest ones;
+    /// the older due ticks (e.g. missed during a failover) are recorded as skipped
+    /// in a single history record.
+    /// The latest due tick is run even if 0
+    pub catch_up: usize,
+
+    /// number of history records kept per job
+    pub history_limit: usize,
+
+    /// how often a started scheduler checks for due ticks at the latest
+    pub poll_interval: Duration,
+}
+
+impl Default for SchedulerOptions {
+    fn default() -> Self {
+        SchedulerOptions{
+            catch_up: 5,
+            history_limit: 100,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How a tick of a job ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    Succeeded,
+
+    /// the job returned an error with the given message
+    Failed(String),
+
+    /// the ticks from the record's one up to the given one (both included)
+    /// were missed and not caught up
+    Skipped(DateTime<Utc>),
+}
+
+/// Entry of a job's run history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunRecord {
+    pub tick: DateTime<Utc>,
+    pub outcome: RunOutcome,
+}
+
+type Job = Box<dyn FnMut(DateTime<Utc>) -> std::result::Result<(), String> + Send>;
+
+struct ScheduledJob {
+    name: String,
+    schedule: Schedule,
+    lock: Mutex,
+    job: Job,
+}
+
+/// Distributed scheduler of periodic jobs.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::scheduler::{RunOutcome,Scheduler};
+/// use std::sync::{Arc,Mutex};
+/// use std::time::Duration;
+/// let client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let another_client = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let ticks = Arc::new(Mutex::new(Vec::new()));
+///
+/// // two replicas scheduling the same job
+/// let mut replicas = Vec::new();
+/// for client in [&client, &another_client].iter() {
+///     let mut scheduler = Scheduler::new(client, "/scheduler").unwrap();
+///     let ticks = ticks.clone();
+///     scheduler.schedule("report", "* * * * * *".parse().unwrap(), move |tick| {
+///         ticks.lock().unwrap().push(tick);
+///         Ok(())
+///     }).unwrap();
+///     replicas.push(scheduler);
+/// }
+///
+/// for _ in 0..10 {
+///     for replica in replicas.iter_mut() {
+///         replica.run_pending().unwrap();
+///     }
+///     std::thread::sleep(Duration::from_millis(300));
+/// }
+///
+/// // each tick ran exactly once
+/// let mut ticks = ticks.lock().unwrap().clone();
+/// let runs = ticks.len();
+/// ticks.dedup();
+/// assert!(runs > 0);
+/// assert_eq!(ticks.len(), runs);
+///
+/// let history = replicas[0].history("report").unwrap();
+/// assert_eq!(history.iter().filter(|record| record.outcome == RunOutcome::Succeeded).count(), runs);
+///
+/// # drop(replicas);
+/// # client.erase("/scheduler", 0);
+/// ```
+pub struct Scheduler {
+    client: Client,
+    prefix: String,
+    options: SchedulerOptions,
+    jobs: Vec<ScheduledJob>,
+}
+
+/// Scheduler running in the background, see `Scheduler::start`. Stops on drop.
+pub struct SchedulerHandle {
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+fn encode_outcome(tick: DateTime<Utc>, outcome: &RunOutcome) -> String {
+    match outcome {
+        RunOutcome::Succeeded => format!("{} succeeded", tick.timestamp()),
+        RunOutcome::Failed(message) => format!("{} failed {}", tick.timestamp(), message),
+        RunOutcome::Skipped(last) => format!("{} skipped {}", tick.timestamp(), last.timestamp()),
+    }
+}
+
+fn decode_outcome(record: &str) -> Option<RunRecord> {
+    let mut parts = record.splitn(3, ' ');
+    let timestamp = |timestamp: &str| Utc.timestamp_opt(timestamp.parse().ok()?, 0).single();
+    let tick = timestamp(parts.next()?)?;
+
+    let outcome = match parts.next()? {
+        "succeeded" => RunOutcome::Succeeded,
+        "failed" => RunOutcome::Failed(String::from(parts.next().unwrap_or(""))),
+        "skipped" => RunOutcome::Skipped(timestamp(parts.next()?)?),
+        _ => return None,
+    };
+    Some(RunRecord{tick, outcome})
+}
+
+fn decode_last_run(last_run: &str) -> Result<DateTime<Utc>> {
+    last_run.parse().ok()
+        .and_then(|last_run| Utc.timestamp_opt(last_run, 0).single())
+        .ok_or_else(|| OffkvError::Decode(String::from("the last run is not a timestamp")))
+}
+
+impl Scheduler {
+    /// Creates a scheduler with default options storing its state under the prefix.
+    ///
+    /// # Arguments:
+    ///
+    /// * `client` - client the scheduler is operated with
+    /// * `prefix` - key the jobs' state is stored under
+    pub fn new(client: &Client, prefix: &str) -> Result<Self> {
+        Scheduler::with_options(client, prefix, SchedulerOptions::default())
+    }
+
+    /// Creates a scheduler storing its state under the prefix.
+    pub fn with_options(client: &Client, prefix: &str, options: SchedulerOptions) -> Result<Self> {
+        recipes::ensure_path(client, prefix)?;
+        Ok(Scheduler{client: client.clone(), prefix: String::from(prefix), options, jobs: Vec::new()})
+    }
+
+    /// Adds a job. Ticks before the first schedule of the job by any replica are not run.
+    ///
+    /// # Arguments:
+    ///
+    /// * `name` - name of the job, the same on all replicas
+    /// * `schedule` - cron schedule of the job (in UTC), e.g. `"0 */5 * * * *".parse()`
+    /// * `job` - the job, called with the tick it's run for
+    pub fn schedule<F>(&mut self, name: &str, schedule: Schedule, job: F) -> Result<()>
+        where F: FnMut(DateTime<Utc>) -> std::result::Result<(), String> + Send + 'static {
+
+        let key = format!("{}/{}", self.prefix, name);
+        recipes::ensure_path(&self.client, &format!("{}/history", key))?;
+
+        match self.client.create(&format!("{}/last_run", key), Utc::now().timestamp().to_string(), false) {
+            Ok(_) | Err(OffkvError::EntryExists) => {},
+            Err(error) => return Err(error),
+        }
+
+        self.jobs.push(ScheduledJob{
+            name: String::from(name),
+            schedule,
+            lock: Mutex::new(&self.client, &format!("{}/lock", key))?,
+            job: Box::new(job),
+        });
+        Ok(())
+    }
+
+    /// Runs the due ticks of the jobs not taken by other replicas.
+    ///
+    /// # Returns:
+    ///
+    /// * number of job runs
+    /// * `OffkvError::Decode` if the last-run key of a job is corrupt
+    pub fn run_pending(&mut self) -> Result<usize> {
+        let mut runs = 0;
+        for index in 0..self.jobs.len() {
+            runs += self.run_job(index)?;
+        }
+        Ok(runs)
+    }
+
+    /// Returns the run history of the job, the oldest records first.
+    pub fn history(&self, name: &str) -> Result<Vec<RunRecord>> {
+        let history = format!("{}/{}/history", self.prefix, name);
+        let mut records = Vec::new();
+
+        for record in recipes::sequential_children(&self.client, &history, "run-")? {
+            match self.client.get(&record, false) {
+                Ok((_, record, _)) => records.extend(decode_outcome(&record)),
+                Err(OffkvError::NoEntry) => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Moves the scheduler to a background thread checking for due ticks
+    /// at each tick and every `poll_interval`.
+    pub fn start(mut self) -> SchedulerHandle {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&stopped);
+
+        let thread = thread::Builder::new()
+            .name(String::from("rsoffkv-scheduler"))
+            .spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    // failures (e.g. a lost connection) are retried on the next check
+                    let _ = self.run_pending();
+
+                    let now = Utc::now();
+                    let next = self.jobs.iter()
+                        .filter_map(|job| job.schedule.after(&now).next())
+                        .min()
+                        .and_then(|next| (next - now).to_std().ok())
+                        .map_or(self.options.poll_interval, |next| next.min(self.options.poll_interval));
+                    thread::sleep(next);
+                }
+            })
+            .expect("Failed to spawn scheduler");
+
+        SchedulerHandle{stopped, thread: Some(thread)}
+    }
+
+    fn run_job(&mut self, index: usize) -> Result<usize> {
+        let key = format!("{}/{}", self.prefix, self.jobs[index].name);
+        let last_run_key = format!("{}/last_run", key);
+        let now = Utc::now();
+
+        // cheap check before taking the lock
+        let (_, last_run, _) = self.client.get(&last_run_key, false)?;
+        let last_run = decode_last_run(&last_run)?;
+        if self.jobs[index].schedule.after(&last_run).next().is_none_or(|tick| tick > now) {
+            return Ok(0);
+        }
+
+        let scheduled = &mut self.jobs[index];
+        let _guard = match scheduled.lock.try_lock()? {
+            Some(guard) => guard,
+            // another replica is on it
+            None => return Ok(0),
+        };
+
+        let (version, last_run, _) = self.client.get(&last_run_key, false)?;
+        let last_run = decode_last_run(&last_run)?;
+
+        // the ticks to run are taken back from the latest due one, one more tells whether some are skipped
+        let catch_up = self.options.catch_up.max(1);
+        let mut due: Vec<DateTime<Utc>> = scheduled.schedule.after(&now).rev()
+            .take_while(|tick| *tick > last_run)
+            .take(catch_up + 1)
+            .collect();
+        let skipped = match due.len() > catch_up {
+            true => due.pop().and_then(|last| Some((scheduled.schedule.after(&last_run).next()?, last))),
+            false => None,
+        };
+        due.reverse();
+        let latest = match due.last() {
+            Some(latest) => *latest,
+            None => return Ok(0),
+        };
+
+        // claim all the due ticks at once
+        if self.client.cas(&last_run_key, latest.timestamp().to_string(), version)? == 0 {
+            return Ok(0);
+        }
+
+        let mut records = Vec::new();
+        if let Some((first, last)) = skipped {
+            records.push(encode_outcome(first, &RunOutcome::Skipped(last)));
+        }
+        let runs = due.len();
+        for tick in due {
+            let outcome = match (scheduled.job)(tick) {
+                Ok(()) => RunOutcome::Succeeded,
+                Err(message) => RunOutcome::Failed(message),
+            };
+            records.push(encode_outcome(tick, &outcome));
+        }
+
+        let history = format!("{}/history", key);
+        for record in records {
+            self.client.create_sequential(&format!("{}/run-", history), record, false)?;
+        }
+
+        // trim the history
+        let kept = recipes::sequential_children(&self.client, &history, "run-")?;
+        for record in kept.iter().take(kept.len().saturating_sub(self.options.history_limit)) {
+            match self.client.erase(record, 0) {
+                Ok(()) | Err(OffkvError::NoEntry) => {},
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(runs)
+    }
+}
+
+impl SchedulerHandle {
+    /// Stops the scheduler, waiting for the running job to complete.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for SchedulerHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(outcome: RunOutcome) {
+        let tick = Utc.timestamp_opt(1_600_000_000, 0).unwrap();
+        let record = decode_outcome(&encode_outcome(tick, &outcome)).unwrap();
+        assert_eq!(record, RunRecord{tick, outcome});
+    }
+
+    #[test]
+    fn outcomes_round_trip() {
+        round_trip(RunOutcome::Succeeded);
+        round_trip(RunOutcome::Failed(String::from("disk full, retry later")));
+        round_trip(RunOutcome::Failed(String::new()));
+        round_trip(RunOutcome::Skipped(Utc.timestamp_opt(1_600_000_000, 0).unwrap()));
+        round_trip(RunOutcome::Skipped(Utc.timestamp_opt(1_600_086_400, 0).unwrap()));
+    }
+
+    #[test]
+    fn malformed_records_are_rejected() {
+        assert_eq!(decode_outcome(""), None);
+        assert_eq!(decode_outcome("yesterday succeeded"), None);
+        assert_eq!(decode_outcome("1600000000"), None);
+        assert_eq!(decode_outcome("1600000000 exploded"), None);
+        assert_eq!(decode_outcome("1600000000 skipped"), None);
+        assert_eq!(decode_outcome("1600000000 skipped many"), None);
+    }
+
+    #[test]
+    fn corrupt_last_run_is_a_decode_error() {
+        assert_eq!(decode_last_run("1600000000").unwrap(), Utc.timestamp_opt(1_600_000_000, 0).unwrap());
+        match decode_last_run("garbage") {
+            Err(OffkvError::Decode(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
@@ -1,3 +1,16 @@
+use std::collections::BTreeMap;
+
+use crate::client::Client;
+use crate::result::OffkvError;
+
+mod two_phase;
+
+pub use two_phase::{Coordinator,RecoveryReport};
+
+
+type Result<T> = std::result::Result<T, OffkvError>;
+
+
 /// Transaction structure.
 ///
 /// Each transaction consists of two parts:
@@ -25,7 +38,9 @@ pub struct TxnCheck<'a> {
 
 /// Transaction operation.
 ///
-/// There are 3 possible operations in rsoffkv transaction: Create, Set or Erase
+/// There are 3 possible operations in rsoffkv transaction: Create, Set or Erase.
+/// Create and Set take string values, `CreateBytes` and `SetBytes` are their
+/// counterparts taking arbitrary bytes.
 pub enum TxnOp<'a> {
     /// Creates the key, rolls back if the key already exists or
     /// preceding entry does not exist.
@@ -38,6 +53,47 @@ pub enum TxnOp<'a> {
 
     /// Erase - deletes the key, rolls back if the key does not exist
     Erase  { key: &'a str },
+
+    /// Create with a value of arbitrary bytes
+    CreateBytes { key: &'a str, value: &'a [u8], leased: bool },
+
+    /// Set with a value of arbitrary bytes
+    SetBytes { key: &'a str, value: &'a [u8] },
+}
+
+impl<'a> TxnOp<'a> {
+    /// Returns `TxnOp::CreateBytes`, the value being a string or bytes.
+    pub fn create<V: AsRef<[u8]> + ?Sized>(key: &'a str, value: &'a V, leased: bool) -> Self {
+        TxnOp::CreateBytes{key, value: value.as_ref(), leased}
+    }
+
+    /// Returns `TxnOp::SetBytes`, the value being a string or bytes.
+    pub fn set<V: AsRef<[u8]> + ?Sized>(key: &'a str, value: &'a V) -> Self {
+        TxnOp::SetBytes{key, value: value.as_ref()}
+    }
+
+    /// Returns `TxnOp::Erase`.
+    pub fn erase(key: &'a str) -> Self {
+        TxnOp::Erase{key}
+    }
+
+    /// Returns the operation with the value as bytes.
+    pub(crate) fn bytes(&self) -> BytesOp<'a> {
+        match *self {
+            TxnOp::Create{key, value, leased} => BytesOp::Create{key, value: value.as_bytes(), leased},
+            TxnOp::CreateBytes{key, value, leased} => BytesOp::Create{key, value, leased},
+            TxnOp::Set{key, value} => BytesOp::Set{key, value: value.as_bytes()},
+            TxnOp::SetBytes{key, value} => BytesOp::Set{key, value},
+            TxnOp::Erase{key} => BytesOp::Erase{key},
+        }
+    }
+}
+
+/// Transaction operation with the value as bytes, see `TxnOp::bytes`.
+pub(crate) enum BytesOp<'a> {
+    Create { key: &'a str, value: &'a [u8], leased: bool },
+    Set { key: &'a str, value: &'a [u8] },
+    Erase { key: &'a str },
 }
 
 /// Transaction operation result.
@@ -51,3 +107,90 @@ pub enum TxnOpResult {
     /// new version after Set
     Set(i64),
 }
+
+
+/// Buffered optimistic transaction, see `Client::transact`.
+///
+/// Reads go to the store (once per key) and remember the versions seen,
+/// writes are buffered until the closure returns and then committed in
+/// a single `Transaction` checking that none of the keys read has changed.
+pub struct TxnContext<'a> {
+    client: &'a Client,
+    reads: BTreeMap<String, (i64, Option<Vec<u8>>)>,
+    writes: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl<'a> TxnContext<'a> {
+    pub(crate) fn new(client: &'a Client) -> Self {
+        TxnContext{client, reads: BTreeMap::new(), writes: Vec::new()}
+    }
+
+    /// Returns the value of the key (`None` if it doesn't exist) as seen by the transaction,
+    /// i.e. taking its own buffered writes into account.
+    pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        if let Some((_, value)) = self.writes.iter().find(|(written, _)| written == key) {
+            return Ok(value.clone());
+        }
+
+        Ok(self.read(key)?.1.clone())
+    }
+
+    /// Assigns the value to the key (creating it if needed) on commit.
+    pub fn set<V: AsRef<[u8]>>(&mut self, key: &str, value: V) {
+        self.write(key, Some(value.as_ref().to_vec()));
+    }
+
+    /// Erases the key (if it exists) on commit.
+    pub fn erase(&mut self, key: &str) {
+        self.write(key, None);
+    }
+
+    fn write(&mut self, key: &str, value: Option<Vec<u8>>) {
+        match self.writes.iter_mut().find(|(written, _)| written == key) {
+            Some((_, buffered)) => *buffered = value,
+            None => self.writes.push((String::from(key), value)),
+        }
+    }
+
+    fn read(&mut self, key: &str) -> Result<&(i64, Option<Vec<u8>>)> {
+        if !self.reads.contains_key(key) {
+            let read = match self.client.get_bytes(key, false) {
+                Ok((version, value, _)) => (version, Some(value)),
+                Err(OffkvError::NoEntry) => (0, None),
+                Err(error) => return Err(error),
+            };
+            self.reads.insert(String::from(key), read);
+        }
+
+        Ok(&self.reads[key])
+    }
+
+    /// Commits the buffered writes, returns `false` if a key read has been changed.
+    pub(crate) fn commit(mut self) -> Result<bool> {
+        // whether a write creates the key depends on its existence
+        let written: Vec<String> = self.writes.iter().map(|(key, _)| key.clone()).collect();
+        for key in written.iter() {
+            self.read(key)?;
+        }
+
+        let checks = self.reads.iter()
+            .filter(|(_, (version, _))| *version != 0)
+            .map(|(key, (version, _))| TxnCheck{key, version: *version})
+            .collect();
+
+        let ops = self.writes.iter()
+            .filter_map(|(key, value)| match (self.reads[key].0, value) {
+                (0, Some(value)) => Some(TxnOp::CreateBytes{key, value, leased: false}),
+                (_, Some(value)) => Some(TxnOp::SetBytes{key, value}),
+                (0, None) => None,
+                (_, None) => Some(TxnOp::Erase{key}),
+            })
+            .collect();
+
+        match self.client.commit(Transaction{checks, ops}) {
+            Ok(_) => Ok(true),
+            Err(OffkvError::TxnFailed(_)) => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+}
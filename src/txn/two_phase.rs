@@ -0,0 +1,512 @@
+use std::collections::{BTreeMap,BTreeSet};
+use std::convert::TryInto;
+
+use super::*;
+
+use crate::recipes;
+
+
+/// Outcome of `Coordinator::recover`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// in-doubt transactions completed according to the recorded decision to commit
+    pub committed: usize,
+
+    /// in-doubt transactions rolled back
+    pub aborted: usize,
+
+    /// transactions left in the log since some of their participants are not registered
+    /// or their operations failed to apply
+    pub unresolved: usize,
+}
+
+/// Two-phase commit of `Transaction`s spanning several clients (e.g. clusters).
+///
+/// 1. A record of the transaction is created in the log (a key of the log client)
+///    in the _preparing_ state.
+/// 2. Each participant commits its transaction's checks together with a prepare record
+///    holding the operations and a lock (under `<prefix>/locks`) for every key checked or
+///    written; a failure on any participant aborts the transaction.
+/// 3. The decision to commit is recorded in the log with `cas`.
+/// 4. Each participant commits the operations together with the erasure of its prepare record
+///    and locks, which makes applying idempotent. Then the log record is erased.
+///
+/// If the coordinator crashes, `recover` completes transactions with the recorded decision
+/// to commit and aborts the others. Checks are verified on prepare only; the locks keep
+/// other transactions of coordinators from preparing on the same keys until then, but
+/// writes around the coordinator are not locked out, so keys updated through the
+/// coordinator should not be written around it. An operation failing on apply
+/// (e.g. `Create` of an existing key) fails `execute`, leaving the decision in the log.
+///
+/// Each step commits a single transaction per participant: the prepare holds the checks,
+/// the prepare record and a lock per key, and the apply holds a check of the prepare record,
+/// the operations and the erasure of the record and the locks. Consul limits a transaction
+/// to 64 operations (checks included), so there a participant's transaction should touch
+/// at most 31 distinct keys (each written at most once). A bigger one may prepare but fail
+/// to apply, like an operation failing on apply.
+///
+/// # Example:
+/// ```
+/// # use rsoffkv::client::Client;
+/// use rsoffkv::result::OffkvError;
+/// use rsoffkv::txn::{Coordinator,RecoveryReport,Transaction,TxnCheck,TxnOp};
+/// let orders = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+/// let billing = Client::new("consul://localhost:8500", "/test_prefix").unwrap();
+///
+/// let order_version = orders.create("/order", "new", false).unwrap();
+/// billing.create("/invoice", "draft", false).unwrap();
+///
+/// let mut coordinator = Coordinator::new(&orders, "/2pc/log").unwrap();
+/// coordinator.add_participant("orders", &orders, "/2pc/orders").unwrap();
+/// coordinator.add_participant("billing", &billing, "/2pc/billing").unwrap();
+///
+/// // both updates are applied
+/// coordinator.execute(vec![
+///     ("orders", Transaction{
+///         checks: vec![TxnCheck{key: "/order", version: order_version}],
+///         ops: vec![TxnOp::Set{key: "/order", value: "paid"}],
+///     }),
+///     ("billing", Transaction{
+///         checks: vec![],
+///         ops: vec![TxnOp::Set{key: "/invoice", value: "issued"}],
+///     }),
+/// ]).unwrap();
+/// assert_eq!(orders.get("/order", false).unwrap().1, "paid");
+/// assert_eq!(billing.get("/invoice", false).unwrap().1, "issued");
+///
+/// // a stale check on one participant aborts the whole transaction
+/// match coordinator.execute(vec![
+///     ("billing", Transaction{
+///         checks: vec![],
+///         ops: vec![TxnOp::Set{key: "/invoice", value: "void"}],
+///     }),
+///     ("orders", Transaction{
+///         checks: vec![TxnCheck{key: "/order", version: order_version}],
+///         ops: vec![TxnOp::Set{key: "/order", value: "cancelled"}],
+///     }),
+/// ]) {
+///     Err(OffkvError::TxnFailed(_)) => {},
+///     _ => panic!("the transaction must fail"),
+/// }
+/// assert_eq!(billing.get("/invoice", false).unwrap().1, "issued");
+///
+/// // nothing is in doubt
+/// assert_eq!(coordinator.recover().unwrap(), RecoveryReport::default());
+///
+/// # orders.erase("/order", 0);
+/// # orders.erase("/invoice", 0);
+/// # orders.erase("/2pc", 0);
+/// ```
+pub struct Coordinator {
+    log: Client,
+    log_key: String,
+    participants: BTreeMap<String, (Client, String)>,
+}
+
+const PREPARING: &str = "preparing";
+const COMMITTED: &str = "committed";
+const ABORTED: &str = "aborted";
+const LOCKS: &str = "locks";
+
+/// Owned copy of a `Transaction`, stored in prepare records.
+#[derive(Debug, PartialEq, Eq)]
+struct Prepared {
+    checks: Vec<(String, i64)>,
+    ops: Vec<PreparedOp>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum PreparedOp {
+    Create(String, Vec<u8>, bool),
+    Set(String, Vec<u8>),
+    Erase(String),
+}
+
+fn put_bytes(encoded: &mut Vec<u8>, bytes: &[u8]) {
+    encoded.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    encoded.extend_from_slice(bytes);
+}
+
+fn take_bytes<'a>(encoded: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if encoded.len() < n {
+        return Err(OffkvError::Decode(String::from("truncated prepare record")));
+    }
+    let (taken, rest) = encoded.split_at(n);
+    *encoded = rest;
+    Ok(taken)
+}
+
+fn take_u32(encoded: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_be_bytes(take_bytes(encoded, 4)?.try_into().unwrap()))
+}
+
+fn take_string(encoded: &mut &[u8]) -> Result<String> {
+    let n = take_u32(encoded)? as usize;
+    Ok(String::from_utf8_lossy(take_bytes(encoded, n)?).into_owned())
+}
+
+fn take_value(encoded: &mut &[u8]) -> Result<Vec<u8>> {
+    let n = take_u32(encoded)? as usize;
+    Ok(take_bytes(encoded, n)?.to_vec())
+}
+
+impl Prepared {
+    fn new(transaction: &Transaction) -> Self {
+        Prepared{
+            checks: transaction.checks.iter().map(|check| (String::from(check.key), check.version)).collect(),
+            ops: transaction.ops.iter().map(|op| match op.bytes() {
+                BytesOp::Create{key, value, leased} => PreparedOp::Create(String::from(key), value.to_vec(), leased),
+                BytesOp::Set{key, value} => PreparedOp::Set(String::from(key), value.to_vec()),
+                BytesOp::Erase{key} => PreparedOp::Erase(String::from(key)),
+            }).collect(),
+        }
+    }
+
+    /// Returns the lock keys of the keys checked or written, each once.
+    fn locks(&self, prefix: &str) -> Vec<String> {
+        let keys = self.checks.iter().map(|(key, _)| key)
+            .chain(self.ops.iter().map(|op| match op {
+                PreparedOp::Create(key, _, _) | PreparedOp::Set(key, _) | PreparedOp::Erase(key) => key,
+            }));
+
+        keys.map(|key| {
+            let hex: String = key.bytes().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{}/{}/{}", prefix, LOCKS, hex)
+        }).collect::<BTreeSet<_>>().into_iter().collect()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::new();
+
+        encoded.extend_from_slice(&(self.checks.len() as u32).to_be_bytes());
+        for (key, version) in self.checks.iter() {
+            put_bytes(&mut encoded, key.as_bytes());
+            encoded.extend_from_slice(&version.to_be_bytes());
+        }
+
+        encoded.extend_from_slice(&(self.ops.len() as u32).to_be_bytes());
+        for op in self.ops.iter() {
+            match op {
+                PreparedOp::Create(key, value, leased) => {
+                    encoded.push(0);
+                    put_bytes(&mut encoded, key.as_bytes());
+                    put_bytes(&mut encoded, value);
+                    encoded.push(*leased as u8);
+                },
+                PreparedOp::Set(key, value) => {
+                    encoded.push(1);
+                    put_bytes(&mut encoded, key.as_bytes());
+                    put_bytes(&mut encoded, value);
+                },
+                PreparedOp::Erase(key) => {
+                    encoded.push(2);
+                    put_bytes(&mut encoded, key.as_bytes());
+                },
+            }
+        }
+        encoded
+    }
+
+    fn decode(mut encoded: &[u8]) -> Result<Self> {
+        let encoded = &mut encoded;
+
+        let mut checks = Vec::new();
+        for _ in 0..take_u32(encoded)? {
+            let key = take_string(encoded)?;
+            checks.push((key, i64::from_be_bytes(take_bytes(encoded, 8)?.try_into().unwrap())));
+        }
+
+        let mut ops = Vec::new();
+        for _ in 0..take_u32(encoded)? {
+            ops.push(match take_bytes(encoded, 1)?[0] {
+                0 => PreparedOp::Create(take_string(encoded)?, take_value(encoded)?, take_bytes(encoded, 1)?[0] != 0),
+                1 => PreparedOp::Set(take_string(encoded)?, take_value(encoded)?),
+                2 => PreparedOp::Erase(take_string(encoded)?),
+                _ => return Err(OffkvError::Decode(String::from("unknown operation in prepare record"))),
+            });
+        }
+        Ok(Prepared{checks, ops})
+    }
+
+    /// Applies the operations together with the erasure of the prepare record and the locks.
+    ///
+    /// Returns `None` if the record is already gone, i.e. the operations have been applied.
+    fn apply(&self, client: &Client, prefix: &str, record: &str, version: i64) -> Result<Option<Vec<TxnOpResult>>> {
+        let mut ops: Vec<TxnOp> = self.ops.iter().map(|op| match op {
+            PreparedOp::Create(key, value, leased) => TxnOp::CreateBytes{key, value, leased: *leased},
+            PreparedOp::Set(key, value) => TxnOp::SetBytes{key, value},
+            PreparedOp::Erase(key) => TxnOp::Erase{key},
+        }).collect();
+        let applied = ops.len();
+
+        let locks = self.locks(prefix);
+        ops.push(TxnOp::erase(record));
+        ops.extend(locks.iter().map(|lock| TxnOp::erase(lock)));
+
+        // only the results of the transaction's own operations are returned
+        Ok(release(client, record, version, ops)?.map(|mut results| {
+            results.truncate(applied);
+            results
+        }))
+    }
+}
+
+/// Commits the operations ending with the erasure of the prepare record, checking its version.
+///
+/// Returns `None` if the record is already gone.
+fn release(client: &Client, record: &str, version: i64, ops: Vec<TxnOp>) -> Result<Option<Vec<TxnOpResult>>> {
+    match client.commit(Transaction{checks: vec![TxnCheck{key: record, version}], ops}) {
+        Ok(results) => Ok(Some(results)),
+        Err(OffkvError::TxnFailed(index)) => match client.exists(record, false)? {
+            (0, _) => Ok(None),
+            _ => Err(OffkvError::TxnFailed(index)),
+        },
+        Err(error) => Err(error),
+    }
+}
+
+fn parse_record(record: &str) -> (String, Vec<String>) {
+    let mut lines = record.lines().map(String::from);
+    (lines.next().unwrap_or_default(), lines.collect())
+}
+
+impl Coordinator {
+    /// Creates a coordinator keeping its log under the key, creating the key
+    /// (and its ancestors) if needed.
+    ///
+    /// # Arguments:
+    ///
+    /// * `log` - client the log is stored with
+    /// * `log_key` - key of the log
+    pub fn new(log: &Client, log_key: &str) -> Result<Self> {
+        recipes::ensure_path(log, log_key)?;
+        Ok(Coordinator{log: log.clone(), log_key: String::from(log_key), participants: BTreeMap::new()})
+    }
+
+    /// Registers a participant. Recovery needs the same participants under the same names.
+    ///
+    /// # Arguments:
+    ///
+    /// * `name` - name of the participant recorded in the log
+    /// * `client` - client the participant's transactions are committed with
+    /// * `prefix` - key of the participant's prepare records
+    pub fn add_participant(&mut self, name: &str, client: &Client, prefix: &str) -> Result<()> {
+        recipes::ensure_path(client, &format!("{}/{}", prefix, LOCKS))?;
+        self.participants.insert(String::from(name), (client.clone(), String::from(prefix)));
+        Ok(())
+    }
+
+    /// Commits the transactions on their participants atomically.
+    ///
+    /// # Arguments:
+    ///
+    /// * `transactions` - pairs of a participant's name and its transaction
+    ///
+    /// # Returns:
+    ///
+    /// * results of the transactions in the same order
+    /// * error of the participant that failed to prepare (`OffkvError::TxnFailed` also if
+    ///   a key is locked by another transaction in progress), or `OffkvError::TxnFailed`
+    ///   if the transaction was aborted by a concurrent `recover`
+    /// * `OffkvError::InvalidArgument` if a participant is not registered or is given
+    ///   more than one transaction
+    pub fn execute(&self, transactions: Vec<(&str, Transaction)>) -> Result<Vec<Vec<TxnOpResult>>> {
+        let participants: Vec<&str> = transactions.iter().map(|(name, _)| *name).collect();
+        for (index, name) in participants.iter().enumerate() {
+            if !self.participants.contains_key(*name) {
+                return Err(OffkvError::InvalidArgument(format!("unknown participant {}", name)));
+            }
+            if participants[..index].contains(name) {
+                return Err(OffkvError::InvalidArgument(format!("participant {} is given several transactions", name)));
+            }
+        }
+
+        let record_value = |state: &str| format!("{}\n{}", state, participants.join("\n"));
+        let (record, mut version, _) = self.log.create_sequential_versioned(
+            &format!("{}/txn-", self.log_key), record_value(PREPARING).as_bytes(), false)?;
+        let id = &record[record.rfind('/').unwrap() + 1..];
+
+        // phase 1: verify the checks and store the operations
+        let mut prepared: Vec<(Prepared, i64)> = Vec::new();
+        for (name, transaction) in transactions.iter() {
+            let (client, prefix) = &self.participants[*name];
+            let prepare = Prepared::new(transaction);
+            let prepare_record = format!("{}/{}", prefix, id);
+            let encoded = prepare.encode();
+            let locks = prepare.locks(prefix);
+
+            let mut ops = vec![TxnOp::create(&prepare_record, &encoded, false)];
+            ops.extend(locks.iter().map(|lock| TxnOp::create(lock, id, false)));
+
+            let outcome = client.commit(Transaction{
+                checks: transaction.checks.iter().map(|check| TxnCheck{key: check.key, version: check.version}).collect(),
+                ops,
+            });
+
+            match outcome {
+                Ok(results) => match results[..] {
+                    [TxnOpResult::Create(version), ..] => prepared.push((prepare, version)),
+                    _ => unreachable!(),
+                },
+                Err(error) => {
+                    // failures are left to `recover`
+                    if let Ok(aborted) = self.log.cas(&record, record_value(ABORTED), version) {
+                        if aborted != 0 && self.abort(id, &participants).is_ok() {
+                            let _ = self.log.erase(&record, aborted);
+                        }
+                    }
+                    return Err(error);
+                },
+            }
+        }
+
+        // the decision
+        version = self.log.cas(&record, record_value(COMMITTED), version)?;
+        if version == 0 {
+            return Err(OffkvError::TxnFailed(0));
+        }
+
+        // phase 2
+        let mut results = Vec::new();
+        for (name, (prepare, prepare_version)) in participants.iter().zip(prepared) {
+            let (client, prefix) = &self.participants[*name];
+            results.push(prepare.apply(client, prefix, &format!("{}/{}", prefix, id), prepare_version)?.unwrap_or_default());
+        }
+
+        self.log.erase(&record, version)?;
+        Ok(results)
+    }
+
+    /// Resolves the transactions left in doubt by crashed coordinators: completes
+    /// the ones with the decision to commit and aborts the others. Must not run
+    /// concurrently with `execute` using the same log, e.g. run it on startup.
+    pub fn recover(&self) -> Result<RecoveryReport> {
+        let mut report = RecoveryReport::default();
+
+        for record in recipes::sequential_children(&self.log, &self.log_key, "txn-")? {
+            let id = &record[record.rfind('/').unwrap() + 1..];
+
+            let (mut version, value, _) = match self.log.get(&record, false) {
+                Ok(read) => read,
+                Err(OffkvError::NoEntry) => continue,
+                Err(error) => return Err(error),
+            };
+            let (mut state, participants) = parse_record(&value);
+            let participants: Vec<&str> = participants.iter().map(String::as_str).collect();
+
+            if participants.iter().any(|name| !self.participants.contains_key(*name)) {
+                report.unresolved += 1;
+                continue;
+            }
+
+            if state == PREPARING {
+                version = self.log.cas(&record, format!("{}\n{}", ABORTED, participants.join("\n")), version)?;
+                // changed concurrently, left for the next recovery
+                if version == 0 {
+                    report.unresolved += 1;
+                    continue;
+                }
+                state = String::from(ABORTED);
+            }
+
+            match state.as_str() {
+                COMMITTED => {
+                    let mut applied = true;
+                    for name in participants.iter() {
+                        let (client, prefix) = &self.participants[*name];
+                        let prepare_record = format!("{}/{}", prefix, id);
+
+                        match client.get_bytes(&prepare_record, false) {
+                            Ok((prepare_version, encoded, _)) => {
+                                let outcome = Prepared::decode(&encoded)
+                                    .and_then(|prepare| prepare.apply(client, prefix, &prepare_record, prepare_version));
+                                applied &= outcome.is_ok();
+                            },
+                            // applied already
+                            Err(OffkvError::NoEntry) => {},
+                            Err(error) => return Err(error),
+                        }
+                    }
+
+                    // the failed participants are retried by the next recovery
+                    if !applied {
+                        report.unresolved += 1;
+                        continue;
+                    }
+                    report.committed += 1;
+                },
+                _ => {
+                    self.abort(id, &participants)?;
+                    report.aborted += 1;
+                },
+            }
+
+            match self.log.erase(&record, version) {
+                Ok(()) | Err(OffkvError::NoEntry) => {},
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Erases the prepare records of the transaction together with their locks.
+    fn abort(&self, id: &str, participants: &[&str]) -> Result<()> {
+        for name in participants.iter() {
+            let (client, prefix) = &self.participants[*name];
+            let prepare_record = format!("{}/{}", prefix, id);
+
+            let (version, encoded, _) = match client.get_bytes(&prepare_record, false) {
+                Ok(read) => read,
+                // not prepared, or aborted already
+                Err(OffkvError::NoEntry) => continue,
+                Err(error) => return Err(error),
+            };
+
+            let locks = Prepared::decode(&encoded)?.locks(prefix);
+            let mut ops = vec![TxnOp::erase(&prepare_record)];
+            ops.extend(locks.iter().map(|lock| TxnOp::erase(lock)));
+            release(client, &prepare_record, version, ops)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepared() -> Prepared {
+        Prepared{
+            checks: vec![(String::from("/order"), 7), (String::from("/stock"), -1)],
+            ops: vec![
+                PreparedOp::Create(String::from("/order/item"), b"\0binary".to_vec(), true),
+                PreparedOp::Set(String::from("/order"), Vec::new()),
+                PreparedOp::Erase(String::from("/cart")),
+            ],
+        }
+    }
+
+    #[test]
+    fn prepare_record_round_trips() {
+        let prepared = prepared();
+        assert_eq!(Prepared::decode(&prepared.encode()).unwrap(), prepared);
+    }
+
+    #[test]
+    fn truncated_prepare_record_is_rejected() {
+        let encoded = prepared().encode();
+        for length in 0..encoded.len() {
+            assert!(Prepared::decode(&encoded[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn every_key_is_locked_once() {
+        assert_eq!(prepared().locks("/2pc/orders"), vec![
+            String::from("/2pc/orders/locks/2f63617274"),
+            String::from("/2pc/orders/locks/2f6f72646572"),
+            String::from("/2pc/orders/locks/2f6f726465722f6974656d"),
+            String::from("/2pc/orders/locks/2f73746f636b"),
+        ]);
+    }
+}